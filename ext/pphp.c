@@ -7,33 +7,41 @@
 #include "php.h"
 #include "ext/standard/info.h"
 #include "php_pphp.h"
-#include "zend_extensions.h"
 #include "zend_ast.h"
 #include "zend_globals.h"
 #include "zend_language_scanner.h"
 #include "zend_language_scanner_defs.h"
 #include "rust_pphp.h"
 
-ZEND_EXTENSION();
-
+/* the zend_ast_process hook that was installed before ours, if any */
 static zend_ast_process_t zend_orig_ast_process;
 
-void pphp_ast_process(zend_ast *ast) {
+static void pphp_ast_process(zend_ast *ast) {
     // call the original processor first
     if (zend_orig_ast_process)
         zend_orig_ast_process(ast);
-    rust_pphp_optimize_ast(ast);
+    // a guard or rewrite callback bailed out (e.g. with a fatal error); it
+    // was caught so that the rules could be released, and is re-raised here
+    if (rust_pphp_optimize_ast(ast))
+        zend_bailout();
 }
 
-void pphp_enable() {
-    // override zend_ast_process
+/* Install the hook once per process; zend_ast_process is a process-wide
+ * global, so under ZTS every thread shares it. */
+static void pphp_enable() {
     zend_orig_ast_process = zend_ast_process;
     zend_ast_process = pphp_ast_process;
 }
 
-void pphp_disable() {
-    // replace original zend_ast_process
-    zend_ast_process = zend_orig_ast_process;
+/* Modules shut down in the reverse order they started, so any extension which
+ * hooked zend_ast_process after us has already put our hook back. If one
+ * didn't, its hook still calls ours, so leave it in place rather than
+ * unhooking it; our module is about to be unloaded either way. */
+static void pphp_disable() {
+    if (zend_ast_process == pphp_ast_process) {
+        zend_ast_process = zend_orig_ast_process;
+    }
+    zend_orig_ast_process = NULL;
 }
 
 PHP_FUNCTION(pphp_add_rule) {
@@ -41,25 +49,241 @@ PHP_FUNCTION(pphp_add_rule) {
     size_t from_len;
     char *to;
     size_t to_len;
+    zval *guard = NULL;
+    char *name = NULL;
+    size_t name_len;
 
-    ZEND_PARSE_PARAMETERS_START(2, 2)
+    ZEND_PARSE_PARAMETERS_START(2, 4)
         Z_PARAM_STRING(from, from_len)
         Z_PARAM_STRING(to, to_len)
+        Z_PARAM_OPTIONAL
+        Z_PARAM_ZVAL_EX(guard, 1, 0)
+        Z_PARAM_STRING_EX(name, name_len, 1, 0)
+    ZEND_PARSE_PARAMETERS_END();
+
+    if (guard && !zend_is_callable(guard, 0, NULL)) {
+        php_error_docref(NULL, E_WARNING, "guard must be a valid callback");
+        RETURN_FALSE;
+    }
+
+    RETURN_BOOL(rust_pphp_add_rule(from, from_len, to, to_len, guard, name));
+}
+
+PHP_FUNCTION(pphp_add_callback_rule) {
+    char *pattern;
+    size_t pattern_len;
+    zval *callback;
+    char *name = NULL;
+    size_t name_len;
+
+    ZEND_PARSE_PARAMETERS_START(2, 3)
+        Z_PARAM_STRING(pattern, pattern_len)
+        Z_PARAM_ZVAL(callback)
+        Z_PARAM_OPTIONAL
+        Z_PARAM_STRING_EX(name, name_len, 1, 0)
+    ZEND_PARSE_PARAMETERS_END();
+
+    if (!zend_is_callable(callback, 0, NULL)) {
+        php_error_docref(NULL, E_WARNING, "callback must be a valid callback");
+        RETURN_FALSE;
+    }
+
+    RETURN_BOOL(rust_pphp_add_callback_rule(pattern, pattern_len, callback, name));
+}
+
+PHP_FUNCTION(pphp_load_rules) {
+    char *path;
+    size_t path_len;
+    zend_long count;
+
+    ZEND_PARSE_PARAMETERS_START(1, 1)
+        Z_PARAM_PATH(path, path_len)
+    ZEND_PARSE_PARAMETERS_END();
+
+    if (php_check_open_basedir(path)) {
+        RETURN_FALSE;
+    }
+
+    count = rust_pphp_load_rules_file(path, 0);
+    if (count < 0) {
+        RETURN_FALSE;
+    }
+    RETURN_LONG(count);
+}
+
+PHP_FUNCTION(pphp_list_rules) {
+    ZEND_PARSE_PARAMETERS_START(0, 0)
+    ZEND_PARSE_PARAMETERS_END();
+
+    array_init(return_value);
+    rust_pphp_list_rules(return_value);
+}
+
+PHP_FUNCTION(pphp_remove_rule) {
+    char *name;
+    size_t name_len;
+
+    ZEND_PARSE_PARAMETERS_START(1, 1)
+        Z_PARAM_STRING(name, name_len)
     ZEND_PARSE_PARAMETERS_END();
 
-    RETURN_BOOL(rust_pphp_add_rule(from, to));
+    RETURN_BOOL(rust_pphp_remove_rule(name));
+}
+
+PHP_FUNCTION(pphp_enable_rule) {
+    char *name;
+    size_t name_len;
+
+    ZEND_PARSE_PARAMETERS_START(1, 1)
+        Z_PARAM_STRING(name, name_len)
+    ZEND_PARSE_PARAMETERS_END();
+
+    RETURN_BOOL(rust_pphp_set_rule_enabled(name, 1));
+}
+
+PHP_FUNCTION(pphp_disable_rule) {
+    char *name;
+    size_t name_len;
+
+    ZEND_PARSE_PARAMETERS_START(1, 1)
+        Z_PARAM_STRING(name, name_len)
+    ZEND_PARSE_PARAMETERS_END();
+
+    RETURN_BOOL(rust_pphp_set_rule_enabled(name, 0));
 }
 
 PHP_FUNCTION(pphp_set_debug_trace) {
     zend_bool enabled;
+    zend_string *name;
 
     ZEND_PARSE_PARAMETERS_START(1, 1)
         Z_PARAM_BOOL(enabled)
     ZEND_PARSE_PARAMETERS_END();
 
-    rust_pphp_set_debug_trace(enabled);
+    /* the same as ini_set(), so it's reset at the end of the request */
+    name = zend_string_init("pphp.debug_trace", sizeof("pphp.debug_trace") - 1, 0);
+    zend_alter_ini_entry_chars(name, enabled ? "1" : "0", 1, PHP_INI_USER, PHP_INI_STAGE_RUNTIME);
+    zend_string_release(name);
 }
 
+/* Parses a boolean INI value the way OnUpdateBool does. */
+static zend_bool pphp_ini_bool(zend_string *value) {
+    if ((ZSTR_LEN(value) == 4 && !strcasecmp(ZSTR_VAL(value), "true"))
+            || (ZSTR_LEN(value) == 3 && !strcasecmp(ZSTR_VAL(value), "yes"))
+            || (ZSTR_LEN(value) == 2 && !strcasecmp(ZSTR_VAL(value), "on"))) {
+        return 1;
+    }
+    return atoi(ZSTR_VAL(value)) != 0;
+}
+
+static ZEND_INI_MH(OnUpdatePphpEnable) {
+    rust_pphp_set_enabled(pphp_ini_bool(new_value));
+    return SUCCESS;
+}
+
+static ZEND_INI_MH(OnUpdatePphpDebugTrace) {
+    rust_pphp_set_debug_trace(pphp_ini_bool(new_value));
+    return SUCCESS;
+}
+
+static ZEND_INI_MH(OnUpdatePphpTraceOutput) {
+    rust_pphp_set_trace_output(ZSTR_VAL(new_value));
+    return SUCCESS;
+}
+
+static ZEND_INI_MH(OnUpdatePphpDisabledRules) {
+    rust_pphp_set_disabled_rules(ZSTR_VAL(new_value));
+    return SUCCESS;
+}
+
+/* Parses an integer INI value. Fails unless the whole value, apart from
+ * surrounding whitespace, is a decimal integer in range. */
+static int pphp_ini_long(zend_string *value, zend_long *result) {
+    const char *start = ZSTR_VAL(value);
+    char *end;
+
+    while (isspace((unsigned char) *start))
+        start++;
+    if (*start == '\0')
+        return FAILURE;
+    errno = 0;
+    *result = ZEND_STRTOL(start, &end, 10);
+    if (errno == ERANGE || end == start)
+        return FAILURE;
+    while (isspace((unsigned char) *end))
+        end++;
+    return *end == '\0' ? SUCCESS : FAILURE;
+}
+
+static ZEND_INI_MH(OnUpdatePphpOptimizationLevel) {
+    zend_long level;
+    if (pphp_ini_long(new_value, &level) == FAILURE)
+        return FAILURE;
+    return rust_pphp_set_optimization_level(level) ? SUCCESS : FAILURE;
+}
+
+static ZEND_INI_MH(OnUpdatePphpUnrollLimit) {
+    zend_long limit;
+    if (pphp_ini_long(new_value, &limit) == FAILURE)
+        return FAILURE;
+    return rust_pphp_set_unroll_limit(limit) ? SUCCESS : FAILURE;
+}
+
+/* {{{ PHP_INI
+ */
+PHP_INI_BEGIN()
+    PHP_INI_ENTRY("pphp.rules_file", "", PHP_INI_SYSTEM, NULL)
+    PHP_INI_ENTRY("pphp.enable", "1", PHP_INI_ALL, OnUpdatePphpEnable)
+    PHP_INI_ENTRY("pphp.debug_trace", "0", PHP_INI_ALL, OnUpdatePphpDebugTrace)
+    PHP_INI_ENTRY("pphp.trace_output", "stdout", PHP_INI_SYSTEM, OnUpdatePphpTraceOutput)
+    PHP_INI_ENTRY("pphp.disabled_rules", "", PHP_INI_ALL, OnUpdatePphpDisabledRules)
+    PHP_INI_ENTRY("pphp.optimization_level", "2", PHP_INI_ALL, OnUpdatePphpOptimizationLevel)
+    PHP_INI_ENTRY("pphp.unroll_limit", "8", PHP_INI_ALL, OnUpdatePphpUnrollLimit)
+PHP_INI_END()
+/* }}} */
+
+/* {{{ PHP_MINIT_FUNCTION
+ */
+PHP_MINIT_FUNCTION(pphp)
+{
+    uint32_t i;
+    uint16_t kind;
+    const char *kind_name;
+    char const_name[64];
+    char *rules_file;
+
+    REGISTER_INI_ENTRIES();
+
+    /* PPHP_AST_CALL etc., for the kind of nodes in array ASTs */
+    for (i = 0; (kind_name = rust_pphp_ast_kind_name(i, &kind)) != NULL; ++i) {
+        size_t len = snprintf(const_name, sizeof(const_name), "PPHP_%s", kind_name + sizeof("ZEND_") - 1);
+        zend_register_long_constant(const_name, len, kind, CONST_CS | CONST_PERSISTENT, module_number);
+    }
+
+    /* load rules now, so they apply to the first script compiled */
+    rules_file = INI_STR("pphp.rules_file");
+    if (rules_file && *rules_file) {
+        rust_pphp_load_rules_file(rules_file, 1);
+    }
+
+    pphp_enable();
+
+    return SUCCESS;
+}
+/* }}} */
+
+/* {{{ PHP_MSHUTDOWN_FUNCTION
+ */
+PHP_MSHUTDOWN_FUNCTION(pphp)
+{
+    pphp_disable();
+
+    UNREGISTER_INI_ENTRIES();
+
+    return SUCCESS;
+}
+/* }}} */
+
 /* {{{ PHP_RINIT_FUNCTION
  */
 PHP_RINIT_FUNCTION(pphp)
@@ -68,7 +292,16 @@ PHP_RINIT_FUNCTION(pphp)
     ZEND_TSRMLS_CACHE_UPDATE();
 #endif
 
-    pphp_enable();
+    return SUCCESS;
+}
+/* }}} */
+
+/* {{{ PHP_RSHUTDOWN_FUNCTION
+ */
+PHP_RSHUTDOWN_FUNCTION(pphp)
+{
+    /* rules added by this request don't outlive it */
+    rust_pphp_request_shutdown();
 
     return SUCCESS;
 }
@@ -81,14 +314,35 @@ PHP_MINFO_FUNCTION(pphp)
     php_info_print_table_start();
     php_info_print_table_header(2, "pphp support", "enabled");
     php_info_print_table_end();
+
+    DISPLAY_INI_ENTRIES();
 }
 /* }}} */
 
 /* {{{ arginfo
  */
-ZEND_BEGIN_ARG_INFO(arginfo_pphp_add_rule, 0)
+ZEND_BEGIN_ARG_INFO_EX(arginfo_pphp_add_rule, 0, 0, 2)
     ZEND_ARG_INFO(0, fromPattern)
     ZEND_ARG_INFO(0, toPattern)
+    ZEND_ARG_INFO(0, guard)
+    ZEND_ARG_INFO(0, name)
+ZEND_END_ARG_INFO()
+
+ZEND_BEGIN_ARG_INFO_EX(arginfo_pphp_add_callback_rule, 0, 0, 2)
+    ZEND_ARG_INFO(0, pattern)
+    ZEND_ARG_INFO(0, callback)
+    ZEND_ARG_INFO(0, name)
+ZEND_END_ARG_INFO()
+
+ZEND_BEGIN_ARG_INFO(arginfo_pphp_load_rules, 0)
+    ZEND_ARG_INFO(0, path)
+ZEND_END_ARG_INFO()
+
+ZEND_BEGIN_ARG_INFO(arginfo_pphp_list_rules, 0)
+ZEND_END_ARG_INFO()
+
+ZEND_BEGIN_ARG_INFO(arginfo_pphp_rule_name, 0)
+    ZEND_ARG_INFO(0, name)
 ZEND_END_ARG_INFO()
 
 ZEND_BEGIN_ARG_INFO(arginfo_pphp_set_debug_trace, 0)
@@ -100,6 +354,12 @@ ZEND_END_ARG_INFO()
  */
 const zend_function_entry pphp_functions[] = {
     PHP_FE(pphp_add_rule, arginfo_pphp_add_rule)
+    PHP_FE(pphp_add_callback_rule, arginfo_pphp_add_callback_rule)
+    PHP_FE(pphp_load_rules, arginfo_pphp_load_rules)
+    PHP_FE(pphp_list_rules, arginfo_pphp_list_rules)
+    PHP_FE(pphp_remove_rule, arginfo_pphp_rule_name)
+    PHP_FE(pphp_enable_rule, arginfo_pphp_rule_name)
+    PHP_FE(pphp_disable_rule, arginfo_pphp_rule_name)
     PHP_FE(pphp_set_debug_trace, arginfo_pphp_set_debug_trace)
     PHP_FE_END
 };
@@ -111,10 +371,10 @@ zend_module_entry pphp_module_entry = {
     STANDARD_MODULE_HEADER,
     "pphp",                    /* Extension name */
     pphp_functions,            /* zend_function_entry */
-    NULL,                            /* PHP_MINIT - Module initialization */
-    NULL,                            /* PHP_MSHUTDOWN - Module shutdown */
+    PHP_MINIT(pphp),            /* PHP_MINIT - Module initialization */
+    PHP_MSHUTDOWN(pphp),        /* PHP_MSHUTDOWN - Module shutdown */
     PHP_RINIT(pphp),            /* PHP_RINIT - Request initialization */
-    NULL,                            /* PHP_RSHUTDOWN - Request shutdown */
+    PHP_RSHUTDOWN(pphp),        /* PHP_RSHUTDOWN - Request shutdown */
     PHP_MINFO(pphp),            /* PHP_MINFO - Module info */
     PHP_PPHP_VERSION,        /* Version */
     STANDARD_MODULE_PROPERTIES
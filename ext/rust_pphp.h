@@ -1,8 +1,20 @@
 #ifndef RUST_PPHP_H
 # define RUST_PPHP_H
 
-void rust_pphp_optimize_ast(zend_ast *ast);
-zend_bool rust_pphp_add_rule(char *from, char *to);
+zend_bool rust_pphp_optimize_ast(zend_ast *ast);
+zend_bool rust_pphp_add_rule(char *from, size_t from_len, char *to, size_t to_len, zval *guard, char *name);
+zend_bool rust_pphp_add_callback_rule(char *pattern, size_t pattern_len, zval *callback, char *name);
+zend_bool rust_pphp_remove_rule(char *name);
+zend_bool rust_pphp_set_rule_enabled(char *name, unsigned char enabled);
+void rust_pphp_list_rules(zval *arr);
+zend_long rust_pphp_load_rules_file(char *path, zend_bool persistent);
+void rust_pphp_request_shutdown();
+const char *rust_pphp_ast_kind_name(uint32_t index, uint16_t *kind);
+void rust_pphp_set_enabled(unsigned char enabled);
 void rust_pphp_set_debug_trace(unsigned char enabled);
+void rust_pphp_set_trace_output(const char *output);
+void rust_pphp_set_disabled_rules(const char *names);
+zend_bool rust_pphp_set_optimization_level(zend_long level);
+zend_bool rust_pphp_set_unroll_limit(zend_long limit);
 
 #endif
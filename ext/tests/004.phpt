@@ -0,0 +1,19 @@
+--TEST--
+pphp_add_rule() with a guard callback
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+var_dump(pphp_add_rule('strtoupper(PPHP::$s);', '"CONSTANT";', function ($bindings) {
+    return $bindings['s'] === "'yes'";
+}));
+eval('var_dump(strtoupper("yes"), strtoupper("no"));');
+?>
+--EXPECT--
+bool(true)
+string(8) "CONSTANT"
+string(2) "NO"
@@ -0,0 +1,49 @@
+--TEST--
+pphp_add_callback_rule() rewrites with source and array ASTs
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+function new_api($a, $b) { return "$a-$b"; }
+
+var_dump(pphp_add_callback_rule('old_api(PPHP::$a, PPHP::$b);', function ($node, $bindings) {
+    return 'new_api(PPHP::$b, PPHP::$a);';
+}));
+var_dump(pphp_add_callback_rule('answer();', function ($node, $bindings) {
+    return ['kind' => PPHP_AST_ZVAL, 'attr' => 0, 'value' => 42];
+}));
+var_dump(pphp_add_callback_rule('keep(PPHP::$x);', function ($node, $bindings) {
+    return $bindings['x']['kind'] === PPHP_AST_VAR ? null : 'false;';
+}));
+function keep($x) { return true; }
+// invalid ASTs leave the node unchanged
+pphp_add_callback_rule('bogus();', function ($node, $bindings) {
+    return ['kind' => 4242, 'attr' => 0, 'children' => []];
+});
+pphp_add_callback_rule('obj();', function ($node, $bindings) {
+    return ['kind' => PPHP_AST_ZVAL, 'attr' => 0, 'value' => new stdClass];
+});
+function bogus() { return 'bogus'; }
+function obj() { return 'obj'; }
+
+eval('$y = 1; var_dump(old_api(1, 2), answer(), keep($y), keep(1));');
+eval('var_dump(bogus(), obj());');
+?>
+--EXPECTF--
+bool(true)
+bool(true)
+bool(true)
+string(3) "2-1"
+int(42)
+bool(true)
+bool(false)
+
+Warning: pphp: rewrite callback for 'bogus();' returned an AST with an unknown kind of node; node left unchanged in %s on line %d
+
+Warning: pphp: rewrite callback failed or returned an invalid AST; node left unchanged in %s on line %d
+string(5) "bogus"
+string(3) "obj"
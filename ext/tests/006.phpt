@@ -0,0 +1,25 @@
+--TEST--
+pphp_add_rule() with variadic bindings
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+function new_log($level, ...$parts) { return $level . ': ' . implode(' ', $parts); }
+
+var_dump(pphp_add_rule('old_log(PPHP::$msg, ...PPHP::$rest);', 'new_log("info", PPHP::$msg, ...PPHP::$rest);'));
+var_dump(pphp_add_rule('if (PPHP::$_1) { PPHP::${\'...body\'}; } else { PPHP::${\'...body\'}; }', 'PPHP::$_1; PPHP::${\'...body\'};'));
+eval('var_dump(old_log("a"), old_log("a", "b", "c"));');
+eval('if (print("cond\n")) { echo "one\n"; echo "two\n"; } else { echo "one\n"; echo "two\n"; }');
+?>
+--EXPECT--
+bool(true)
+bool(true)
+string(7) "info: a"
+string(11) "info: a b c"
+cond
+one
+two
@@ -0,0 +1,38 @@
+--TEST--
+pphp_add_rule() with identifier bindings
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+class Api {
+    public function fetchUser($id) { return "user $id"; }
+    public function getUser($id) { return "renamed user $id"; }
+}
+
+var_dump(pphp_add_rule('PPHP::$obj->PPHP__method(PPHP::$arg);', 'PPHP::$obj->PPHP__method(PPHP::$arg, PPHP::$method);'));
+var_dump(pphp_add_rule('$api->fetchUser(PPHP::$id, PPHP::$_);', '$api->getUser(PPHP::$id);'));
+var_dump(pphp_add_rule('function PPHP__name() { return 1; }', 'function PPHP__name() { return PPHP::$name; }'));
+// only names are identifier variables, not strings which look like them
+var_dump(pphp_add_rule('tag("PPHP__x");', '"literal";'));
+function tag($s) { return $s; }
+
+eval('$api = new Api; var_dump($api->fetchUser(1));');
+eval('function traced() { return 1; } var_dump(traced());');
+// modifiers have to match too, or the replacement would drop them
+eval('function &by_ref() { return 1; } var_dump(@by_ref());');
+eval('var_dump(tag("PPHP__x"), tag("other"));');
+?>
+--EXPECT--
+bool(true)
+bool(true)
+bool(true)
+bool(true)
+string(14) "renamed user 1"
+string(6) "traced"
+int(1)
+string(7) "literal"
+string(5) "other"
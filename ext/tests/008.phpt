@@ -0,0 +1,22 @@
+--TEST--
+Rules which rewrite code in a cycle are stopped with a warning
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+function foo($x) { return "foo($x)"; }
+function bar($x) { return "bar($x)"; }
+var_dump(pphp_add_rule('foo(PPHP::$x);', 'bar(PPHP::$x);'));
+var_dump(pphp_add_rule('bar(PPHP::$x);', 'foo(PPHP::$x);'));
+eval('echo foo(1), "\n";');
+?>
+--EXPECTF--
+bool(true)
+bool(true)
+
+Warning: pphp: rules are rewriting code in a cycle and were stopped: custom_1, custom_2 in %s on line %d
+%s(1)
@@ -0,0 +1,54 @@
+--TEST--
+Listing, disabling and removing rules by name
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+var_dump(pphp_add_rule('2 + 2;', '5;', null, 'orwell'));
+var_dump(pphp_add_rule('2 + 2;', '4;', null, 'orwell'));
+var_dump(pphp_add_rule('1 + 1;', '3;'));
+function rule_named($name) {
+    foreach (pphp_list_rules() as $rule) {
+        if ($rule['name'] === $name) {
+            return $rule;
+        }
+    }
+    return null;
+}
+foreach (pphp_list_rules() as $rule) {
+    if (!$rule['persistent'] || $rule['name'] === 'LoopUnroll') {
+        echo $rule['name'], ': ', $rule['description'], $rule['enabled'] ? '' : ' (disabled)', "\n";
+    }
+}
+eval('var_dump(2 + 2, 1 + 1);');
+var_dump(pphp_disable_rule('orwell'));
+eval('var_dump(2 + 2);');
+var_dump(pphp_enable_rule('orwell'));
+var_dump(pphp_remove_rule('orwell'), pphp_remove_rule('orwell'));
+eval('var_dump(2 + 2);');
+var_dump(pphp_disable_rule('BranchElimination'));
+echo rule_named('BranchElimination')['enabled'] ? "enabled\n" : "disabled\n";
+?>
+--EXPECTF--
+bool(true)
+
+Warning: pphp: a rule named 'orwell' already exists in %s on line %d
+bool(false)
+bool(true)
+LoopUnroll: Unrolls loops with a small constant number of iterations (disabled)
+orwell: '2 + 2;' => '5;'
+custom_1: '1 + 1;' => '3;'
+int(5)
+int(3)
+bool(true)
+int(4)
+bool(true)
+bool(true)
+bool(false)
+int(4)
+bool(true)
+disabled
@@ -0,0 +1,33 @@
+--TEST--
+Persistent rules can only be disabled for the current request
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+var_dump(pphp_add_rule('2 + 2;', '5;', null, 'orwell'));
+var_dump(pphp_add_rule('1 + 1;', '3;', null, 'IncrDecr'));
+foreach (pphp_list_rules() as $rule) {
+    if (in_array($rule['name'], ['IncrDecr', 'orwell'])) {
+        echo $rule['name'], $rule['persistent'] ? ' (persistent)' : '', "\n";
+    }
+}
+var_dump(pphp_remove_rule('IncrDecr'));
+var_dump(pphp_disable_rule('IncrDecr'));
+eval('$i = 1; $i += 1; var_dump($i);');
+?>
+--EXPECTF--
+bool(true)
+
+Warning: pphp: a rule named 'IncrDecr' already exists in %s on line %d
+bool(false)
+IncrDecr (persistent)
+orwell
+
+Warning: pphp: 'IncrDecr' is a persistent rule and can't be removed; use pphp_disable_rule instead in %s on line %d
+bool(false)
+bool(true)
+int(2)
@@ -0,0 +1,23 @@
+--TEST--
+Rules loaded from pphp.rules_file apply to the first script compiled
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--INI--
+pphp.rules_file={PWD}/011.rules
+--FILE--
+<?php
+var_dump(strtoupper('pphp'));
+foreach (pphp_list_rules() as $rule) {
+    if (!$rule['persistent'] || strpos($rule['name'], 'custom_') === 0) {
+        echo $rule['name'], ': ', $rule['description'], "\n";
+    }
+}
+?>
+--EXPECT--
+string(24) "PPHP, loaded from a file"
+custom_1: 'strtoupper('pphp');' => ''PPHP, loaded from a file';'
+custom_2: 'PPHP::$a + 0;' => 'PPHP::$a;'
@@ -0,0 +1,47 @@
+--TEST--
+pphp_load_rules() with a .pphp rule file
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+function greet($s) { return "hello $s"; }
+var_dump(pphp_load_rules(__DIR__ . '/012.pphp'));
+foreach (pphp_list_rules() as $rule) {
+    if (!$rule['persistent']) {
+        printf("%s (%d%s): %s\n", $rule['name'], $rule['priority'], $rule['enabled'] ? '' : ', disabled', $rule['description']);
+    }
+}
+eval('var_dump(greet("world"), greet("first"), greet(42));');
+
+$bad = tempnam(sys_get_temp_dir(), 'pphp');
+file_put_contents($bad, "@name ok\nfoo();\n=>\nbar();\n\n@priority high\nfoo();\n=>\nbar();\n");
+var_dump(pphp_load_rules($bad));
+var_dump(count(array_filter(pphp_list_rules(), function ($rule) { return !$rule['persistent']; })));
+
+// a name used twice in the same file is only added once
+file_put_contents($bad, "@name twice\ngreet(1);\n=>\n'one';\n\n@name twice\ngreet(2);\n=>\n'two';\n");
+var_dump(pphp_load_rules($bad));
+eval('var_dump(greet(1), greet(2));');
+unlink($bad);
+?>
+--EXPECTF--
+int(3)
+shout_first (10): 'greet('first');' => ''FIRST!';'
+shout (0): Upper-cases literal greetings
+off (0, disabled): 'greet(42);' => ''never';'
+string(5) "WORLD"
+string(6) "FIRST!"
+string(8) "hello 42"
+
+Warning: pphp: couldn't load rules from %s: line 6: invalid priority 'high'; expected an integer in %s on line %d
+bool(false)
+int(3)
+
+Warning: pphp: a rule named 'twice' already exists in %s on line %d
+int(1)
+string(3) "one"
+string(7) "hello 2"
@@ -0,0 +1,49 @@
+--TEST--
+Invalid patterns are reported when a rule is added
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+var_dump(pphp_add_rule('2 +;', '5;'));
+var_dump(pphp_add_rule('2 + 2;', "foo(\n    1 +\n);"));
+var_dump(pphp_add_rule('foo(PPHP::${"x:nonsense"});', 'bar();'));
+var_dump(pphp_add_rule('foo(PPHP::$x);', 'bar(PPHP::$y);'));
+var_dump(pphp_add_rule('PPHP__fn(PPHP::$x);', 'PPHP__fn(PPHP::$x, PPHP::$x);'));
+var_dump(pphp_add_rule('foo(PPHP::${"...x"});', 'bar([PPHP::${"...x"}]);'));
+var_dump(pphp_add_rule('foo(PPHP::${"...x"});', 'bar(PPHP::${"...x"});'));
+var_dump(pphp_add_callback_rule('foo(;', function ($node) {}));
+pphp_add_callback_rule('foo(PPHP::$x);', function ($node) {
+    return 'bar(PPHP::$y);';
+});
+function foo($x) {
+    return $x;
+}
+eval('var_dump(foo(1));');
+?>
+--EXPECTF--
+Warning: pphp: invalid search pattern: syntax error, unexpected ';' on line 1 in %s on line %d
+bool(false)
+
+Warning: pphp: invalid replacement pattern: syntax error, unexpected ')' on line 3 in %s on line %d
+bool(false)
+
+Warning: pphp: invalid search pattern: invalid constraint in PPHP::${'x:nonsense'} on line 1 in %s on line %d
+bool(false)
+
+Warning: pphp: invalid replacement pattern: PPHP::$y isn't bound by the search pattern in %s on line %d
+bool(false)
+bool(true)
+
+Warning: pphp: invalid replacement pattern: PPHP::$x is bound to a list, so it can only be used in a list in %s on line %d
+bool(false)
+bool(true)
+
+Warning: pphp: invalid pattern: syntax error, unexpected ';' on line 1 in %s on line %d
+bool(false)
+
+Warning: pphp: rewrite callback for 'foo(PPHP::$x);' returned an invalid replacement (PPHP::$y isn't bound by the search pattern); node left unchanged in %s on line %d
+int(1)
@@ -0,0 +1,33 @@
+--TEST--
+Guards can't change rules while they're being applied
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+pphp_add_rule('2 + 2;', '5;', function ($bindings) {
+    var_dump(pphp_add_rule('1 + 1;', '3;'));
+    var_dump(pphp_list_rules());
+    return true;
+}, 'orwell');
+eval('var_dump(2 + 2);');
+var_dump(count(array_filter(pphp_list_rules(), function ($rule) { return !$rule['persistent']; })));
+var_dump(pphp_add_rule('3 + 3;', '7;', null, '100%'));
+var_dump(pphp_add_rule('3 + 3;', '7;', null, '100%'));
+?>
+--EXPECTF--
+Warning: pphp: rules can't be changed or listed by a guard or rewrite callback in %s on line %d
+bool(false)
+
+Warning: pphp: rules can't be changed or listed by a guard or rewrite callback in %s on line %d
+array(0) {
+}
+int(5)
+int(1)
+bool(true)
+
+Warning: pphp: a rule named '100%' already exists in %s on line %d
+bool(false)
@@ -0,0 +1,47 @@
+--TEST--
+Strings with NUL and non-UTF-8 bytes are matched and traced
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+var_dump(pphp_add_rule('strlen("\x00\xff");', '"matched";'));
+eval('var_dump(strlen("\x00\xff"), strlen("\x00\xfe"), strlen("\x00"));');
+
+// A raw NUL byte in the pattern doesn't cut it short
+var_dump(pphp_add_rule("ord('a\0b');", "'raw';"));
+var_dump(pphp_add_callback_rule("chr(\"\0\");", function ($node, $bindings) {
+    return "'callback';";
+}));
+eval("var_dump(ord('a\0b'), ord('a'), chr(\"\0\"), chr(0));");
+
+pphp_add_rule('echo "\x00\xff";', 'echo "ok\n";');
+pphp_set_debug_trace(true);
+eval('echo "\x00\xff";');
+pphp_set_debug_trace(false);
+?>
+--EXPECTF--
+bool(true)
+string(7) "matched"
+int(2)
+int(1)
+bool(true)
+bool(true)
+string(3) "raw"
+int(97)
+string(8) "callback"
+string(1) "%0"
+** PPHP rule match **
+==> matched pattern:
+    ECHO (1 children)
+      ZVAL '\x00\xff' (0 children)
+==> original AST:
+    ECHO (1 children)
+      ZVAL %s (0 children)
+==> new AST:
+    ECHO (1 children)
+      ZVAL 'ok\x0a' (0 children)
+ok
@@ -0,0 +1,53 @@
+--TEST--
+INI settings enable, disable and configure rules
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--INI--
+pphp.disabled_rules=IncrDecr, orwell
+--FILE--
+<?php
+function show_enabled() {
+    foreach (pphp_list_rules() as $rule) {
+        if (in_array($rule['name'], ['BranchElimination', 'IncrDecr', 'orwell', 'custom_1'])) {
+            echo $rule['name'], $rule['enabled'] ? '' : ' (disabled)', "\n";
+        }
+    }
+}
+pphp_add_rule('2 + 2;', '5;', null, 'orwell');
+pphp_add_rule('1 + 1;', '3;');
+show_enabled();
+eval('var_dump(2 + 2, 1 + 1);');
+
+pphp_enable_rule('orwell');
+eval('var_dump(2 + 2);');
+
+ini_set('pphp.enable', '0');
+eval('var_dump(2 + 2, 1 + 1);');
+ini_restore('pphp.enable');
+
+var_dump(ini_set('pphp.optimization_level', '7'));
+var_dump(ini_set('pphp.optimization_level', '0'));
+show_enabled();
+var_dump(ini_set('pphp.trace_output', 'stderr'));
+?>
+--EXPECT--
+BranchElimination
+IncrDecr (disabled)
+orwell (disabled)
+custom_1
+int(4)
+int(3)
+int(5)
+int(4)
+int(2)
+bool(false)
+string(1) "2"
+BranchElimination (disabled)
+IncrDecr (disabled)
+orwell
+custom_1
+bool(false)
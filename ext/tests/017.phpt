@@ -0,0 +1,46 @@
+--TEST--
+Optimization levels select the built-in rules
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--INI--
+pphp.optimization_level=1
+--FILE--
+<?php
+function show_levels() {
+    foreach (pphp_list_rules() as $rule) {
+        if (!in_array($rule['name'], ['BranchElimination', 'IncrDecr', 'LoopUnroll', 'custom_1'])) {
+            continue;
+        }
+        printf("%s: level %s, %s%s\n", $rule['name'], var_export($rule['level'], true),
+            var_export($rule['safety'], true), $rule['enabled'] ? '' : ' (disabled)');
+    }
+}
+pphp_add_rule('2 + 2;', '5;');
+show_levels();
+eval('$x = 1; $x = $x + 1; if (true) { var_dump($x, 2 + 2); }');
+
+ini_set('pphp.optimization_level', '3');
+show_levels();
+ini_set('pphp.optimization_level', '0');
+pphp_enable_rule('IncrDecr');
+show_levels();
+?>
+--EXPECT--
+BranchElimination: level 1, 'safe'
+IncrDecr: level 2, 'unsafe' (disabled)
+LoopUnroll: level 3, 'unsafe' (disabled)
+custom_1: level NULL, NULL
+int(2)
+int(5)
+BranchElimination: level 1, 'safe'
+IncrDecr: level 2, 'unsafe'
+LoopUnroll: level 3, 'unsafe'
+custom_1: level NULL, NULL
+BranchElimination: level 1, 'safe' (disabled)
+IncrDecr: level 2, 'unsafe'
+LoopUnroll: level 3, 'unsafe' (disabled)
+custom_1: level NULL, NULL
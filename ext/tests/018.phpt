@@ -0,0 +1,56 @@
+--TEST--
+LoopUnroll unrolls for loops with constant bounds
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--INI--
+pphp.optimization_level=3
+--FILE--
+<?php
+// only matches once $i has been replaced with its value
+pphp_add_rule('echo 2;', 'echo "two";');
+
+eval('for ($i = 0; $i < 4; $i++) { echo $i; } echo " $i\n";');
+eval('for ($i = 1; $i <= 3; ++$i) echo $i; echo " $i\n";');
+eval('for ($i = 3; $i > 0; $i--) echo $i; echo " $i\n";');
+eval('for ($i = 5; $i < 5; $i++) echo $i; echo " $i\n";');
+eval('for ($i = 0; $i < 3; $i++) { echo $i; while (true) { break; } } echo " $i\n";');
+eval('for ($i = 0; $i < 3; $i++) { $a[$i] = str_repeat("-", $i); echo $i; } echo " ", implode(",", $a), "\n";');
+eval('for ($i = 0; $i < 3; $i++) { echo $i, $i instanceof stdClass ? "!" : ""; } echo " $i\n";');
+
+// not unrolled
+eval('for ($i = 0; $i < 9; $i++) echo $i; echo " $i\n";');
+eval('for ($i = 0; $i < 8; $i++) echo $i; echo " $i\n";');
+eval('for ($i = 0; $i < 3; $i++) { echo $i; if ($i > 5) break; } echo " $i\n";');
+eval('for ($i = 0; $i < 3; $i++) { echo $i; $i += 0; } echo " $i\n";');
+eval('for ($i = 0; $i < 3; $i++) { echo $i; settype($i, "int"); } echo " $i\n";');
+eval('for ($i = 0; $i < 3; $i++) { echo $i; $f = function () use ($i) { return $i; }; } echo " ", $f(), "\n";');
+
+var_dump(ini_set('pphp.unroll_limit', '-1'));
+var_dump(ini_set('pphp.unroll_limit', '10 apples'));
+var_dump(ini_set('pphp.unroll_limit', ''));
+var_dump(ini_set('pphp.unroll_limit', '10'));
+eval('for ($i = 0; $i < 9; $i++) echo $i; echo " $i\n";');
+?>
+--EXPECT--
+01two3 4
+1two3 4
+3two1 0
+ 5
+01two 3
+01two ,-,--
+01two 3
+012345678 9
+01234567 8
+012 3
+012 3
+012 3
+012 2
+bool(false)
+bool(false)
+bool(false)
+string(1) "8"
+01two345678 9
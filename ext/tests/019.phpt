@@ -0,0 +1,43 @@
+--TEST--
+ConstantFolding evaluates operators on literals and built-in constants
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+// only matches once the argument has been folded
+pphp_add_rule('var_dump(7);', 'var_dump("seven");');
+
+$exprs = [
+    '3 + 4', '14 / 2', '15 >> 1', '-(-7)', '~-8', '(int) "7 apples"',
+    '"3.5" * 2', '"1" . "2" == 12', 'PHP_INT_SIZE >= 4 && true',
+    // left alone, since they warn or depend on the precision setting
+    '3 + "4 apples"', '"7" . 0.5',
+];
+foreach ($exprs as $expr) {
+    eval("var_dump($expr);");
+}
+try {
+    eval('var_dump(7 % 0);');
+} catch (DivisionByZeroError $e) {
+    echo $e->getMessage(), "\n";
+}
+?>
+--EXPECTF--
+string(5) "seven"
+string(5) "seven"
+string(5) "seven"
+string(5) "seven"
+string(5) "seven"
+string(5) "seven"
+float(7)
+bool(true)
+bool(true)
+
+Notice: A non well formed numeric value encountered in %s(%d) : eval()'d code on line 1
+int(7)
+string(4) "70.5"
+Modulo by zero
@@ -0,0 +1,37 @@
+--TEST--
+ConditionalElimination only drops operands without side effects
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+function save() { echo "saved\n"; return true; }
+function keep($x) { return "kept"; }
+
+// only matches once an operand has been dropped
+pphp_add_rule('var_dump(false);', 'var_dump("dropped");');
+pphp_add_rule('keep(PPHP::${"x:pure"});', '"pure";');
+
+eval('$x = 5; var_dump($x && false); var_dump(is_int($x) || true, true && $x);');
+eval('var_dump(save() && false);');
+eval('if (save() && false) { echo "then\n"; } else { echo "else\n"; }');
+eval('if (save() || true) { echo "then\n"; }');
+eval('var_dump(keep(1 + 2), keep($x), keep(save()));');
+?>
+--EXPECT--
+string(7) "dropped"
+bool(true)
+bool(true)
+saved
+bool(false)
+saved
+else
+saved
+then
+saved
+string(4) "pure"
+string(4) "pure"
+string(4) "kept"
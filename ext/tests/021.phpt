@@ -0,0 +1,23 @@
+--TEST--
+Rules apply inside namespaces, classes, functions and closures
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+pphp_add_rule('var_dump(7);', 'var_dump("seven");');
+
+eval('namespace A; var_dump(7); namespace B; use A\C; var_dump(7);');
+eval('namespace A { class C { function f() { var_dump(7); } } } namespace { (new A\C)->f(); }');
+eval('function f($x) { while ($x--) { try { var_dump(7); } finally {} } } f(1);');
+eval('$f = function () { return new class { function g() { var_dump(7); } }; }; $f()->g();');
+?>
+--EXPECT--
+string(5) "seven"
+string(5) "seven"
+string(5) "seven"
+string(5) "seven"
+string(5) "seven"
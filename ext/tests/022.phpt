@@ -0,0 +1,33 @@
+--TEST--
+Patterns match names by the symbol they resolve to
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+pphp_add_rule('strlen(PPHP::$s);', '"strlen";');
+pphp_add_rule('Vendor\Lib\Util::run();', '"Util::run";');
+pphp_add_rule('var_dump(PHP_EOL);', 'var_dump("eol");');
+
+eval('var_dump(\strlen("a"));');
+eval('namespace App; var_dump(strlen("a"), \strlen("a")); var_dump(PHP_EOL); var_dump(\PHP_EOL);');
+eval('namespace App\Str { function strlen($s) { return "own"; } }
+      namespace App { use function App\Str\strlen; var_dump(strlen("a")); }');
+eval('namespace Vendor\Lib { class Util { static function run() { return "ran"; } } }
+      namespace App { use Vendor\Lib\Util; use Vendor\Lib as L; var_dump(Util::run(), L\Util::run(), \Vendor\Lib\Util::run()); }
+      namespace Other { class Util { static function run() { return "other"; } } var_dump(Util::run()); }');
+?>
+--EXPECT--
+string(6) "strlen"
+string(6) "strlen"
+string(6) "strlen"
+string(3) "eol"
+string(3) "eol"
+string(3) "own"
+string(9) "Util::run"
+string(9) "Util::run"
+string(9) "Util::run"
+string(5) "other"
@@ -0,0 +1,45 @@
+--TEST--
+ConstantPropagation substitutes constants declared with literals
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+// only matches once the constant has been substituted
+pphp_add_rule('var_dump(5);', 'var_dump("five");');
+pphp_add_rule('var_dump(false);', 'var_dump("gone");');
+
+eval('const FIVE = 5; var_dump(FIVE);');
+// declared by code which has already run
+eval('var_dump(FIVE);');
+eval('namespace App; const N = 2 + 3; var_dump(N); var_dump(\App\N); define("D", 5); var_dump(\D);');
+eval('if (mt_rand(1, 1)) { define("LATER", 5); } var_dump(LATER);');
+eval('try { var_dump(1); } catch (Exception $e) { define("CAUGHT", 5); } var_dump(@CAUGHT);
+      try { throw new Exception; } catch (Exception $e) { define("THROWN", 5); } var_dump(THROWN);');
+// `break` outside a loop is a compile error, unless the branch is removed
+eval('const DEBUG_MODE = false; if (DEBUG_MODE) { break; } echo "no debug\n";');
+eval('final class Config { const N = 5; const DEBUG = false; protected const P = 5;
+          function show() { var_dump(self::N); var_dump(static::N); var_dump(self::P); } }
+      class Open { const N = 5; }
+      var_dump(Config::N); var_dump(Config::DEBUG); (new Config)->show(); var_dump(Open::N);');
+?>
+--EXPECT--
+string(4) "five"
+string(4) "five"
+string(4) "five"
+string(4) "five"
+string(4) "five"
+int(5)
+int(1)
+string(6) "CAUGHT"
+int(5)
+no debug
+string(4) "five"
+string(4) "gone"
+string(4) "five"
+string(4) "five"
+int(5)
+int(5)
@@ -0,0 +1,43 @@
+--TEST--
+FunctionInlining inlines calls to functions which only return an expression
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--INI--
+pphp.optimization_level=3
+--FILE--
+<?php
+// only matches once the call has been inlined
+pphp_add_rule('var_dump(PPHP::$a * PPHP::$a);', 'var_dump("inlined");');
+
+eval('function square($x) { return $x * $x; }
+      $n = 5; var_dump(square($n));
+      // an argument used twice would be evaluated twice
+      var_dump(square($n + 1));
+      function ref_square(&$x) { return $x * $x; }
+      var_dump(ref_square($n));
+      function fact($n) { return $n > 1 ? $n * fact($n - 1) : 1; }
+      var_dump(fact(5));');
+eval('namespace Math {
+          const TWO = 2;
+          function square($x) { return $x * $x; }
+          function twice($x) { return $x * namespace\TWO; }
+      }
+      namespace {
+          $n = 4; var_dump(Math\square($n)); var_dump(Math\twice($n));
+      }');
+
+ini_set('pphp.optimization_level', '2');
+eval('function cube($x) { return $x * $x; } $n = 3; var_dump(cube($n));');
+?>
+--EXPECT--
+string(7) "inlined"
+int(36)
+int(25)
+int(120)
+string(7) "inlined"
+int(8)
+int(9)
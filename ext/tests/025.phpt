@@ -0,0 +1,28 @@
+--TEST--
+InstanceOf replaces calls to the global is_a() by instanceof
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+// only matches once is_a() has been replaced
+pphp_add_rule('var_dump(PPHP::$x instanceof Foo);', 'var_dump("instanceof");');
+
+class Foo {}
+eval('$foo = new Foo; var_dump(is_a($foo, "Foo")); var_dump(\is_a($foo, "foo")); var_dump(is_a($foo, "\\\\Foo"));');
+// allow_string also accepts a class name as the first argument
+eval('var_dump(is_a("Foo", "Foo", true));');
+eval('namespace App; $foo = new \Foo; var_dump(is_a($foo, "Foo")); var_dump(\is_a($foo, "Foo"));');
+eval('namespace Own { function is_a($x, $class) { return "own"; } var_dump(is_a(new \Foo, "Foo")); }');
+?>
+--EXPECT--
+string(10) "instanceof"
+string(10) "instanceof"
+string(10) "instanceof"
+bool(true)
+bool(true)
+string(10) "instanceof"
+string(3) "own"
@@ -0,0 +1,46 @@
+--TEST--
+A variable used more than once in a replacement gets a copy of its node at each use
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+var_dump(pphp_add_rule('sq(PPHP::$x);', 'PPHP::$x * PPHP::$x;'));
+var_dump(pphp_add_rule('dup(PPHP::$x);', 'PPHP::$x . PPHP::$x;'));
+var_dump(pphp_add_rule('pair(PPHP::${"...xs"});', '[args(PPHP::${"...xs"}), args(PPHP::${"...xs"})];'));
+function args(...$xs) { return $xs; }
+
+// folding one copy of the operand mustn't change the other
+eval('var_dump(sq(1 + 2));');
+eval('$s = "ab"; var_dump(dup($s . "cd" . str_repeat("e", 2)));');
+eval('$n = 1; var_dump(pair($n, $n + 1, 2 * 2));');
+?>
+--EXPECT--
+bool(true)
+bool(true)
+bool(true)
+int(9)
+string(12) "abcdeeabcdee"
+array(2) {
+  [0]=>
+  array(3) {
+    [0]=>
+    int(1)
+    [1]=>
+    int(2)
+    [2]=>
+    int(4)
+  }
+  [1]=>
+  array(3) {
+    [0]=>
+    int(1)
+    [1]=>
+    int(2)
+    [2]=>
+    int(4)
+  }
+}
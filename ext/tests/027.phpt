@@ -0,0 +1,38 @@
+--TEST--
+A guard which bails out with a fatal error leaves the rules usable
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+function foo($x) { return "foo $x"; }
+function bar($x) { return "bar $x"; }
+
+$fatal = true;
+var_dump(pphp_add_rule('foo(PPHP::$x);', 'bar(PPHP::$x);', function ($bindings) {
+    global $fatal;
+    if ($fatal) {
+        $fatal = false;
+        trigger_error('guard failed', E_USER_ERROR);
+    }
+    return true;
+}));
+
+register_shutdown_function(function () {
+    var_dump(pphp_add_rule('baz();', 'foo("baz");'));
+    eval('var_dump(foo(2)); var_dump(baz());');
+});
+
+eval('var_dump(foo(1));');
+echo "not reached\n";
+?>
+--EXPECTF--
+bool(true)
+
+Fatal error: guard failed in %s on line %d
+bool(true)
+string(5) "bar 2"
+string(7) "bar baz"
@@ -0,0 +1,69 @@
+--TEST--
+Constrained variables only bind to matching nodes
+--SKIPIF--
+<?php
+if (!extension_loaded('pphp')) {
+	echo 'skip';
+}
+?>
+--FILE--
+<?php
+function s($x) { return 'no'; }
+function i($x) { return 'no'; }
+function f($x) { return 'no'; }
+function c($x) { return 'no'; }
+function v($x) { return 'no'; }
+function k($x) { return 'no'; }
+function m($x) { return 'no'; }
+function id($x) { return $x; }
+class O {
+    function get() { return 1; }
+    static function make() { return 2; }
+}
+
+$constraints = ['string' => 's', 'int' => 'i', 'float' => 'f', 'const' => 'c', 'var' => 'v', 'call' => 'k', 'method_call' => 'm'];
+foreach ($constraints as $constraint => $fn) {
+    var_dump(pphp_add_rule("$fn(PPHP::\${'x:$constraint'});", "'$constraint';"));
+}
+
+$a = 'a';
+$arr = [1];
+$o = new O;
+eval('var_dump(s("a"), s(1), s($a));');
+eval('var_dump(i(1), i("1"), i(1.0));');
+eval('var_dump(f(1.5), f(1), f("1.5"));');
+eval('var_dump(c(FOO), c("FOO"), c($a));');
+eval('var_dump(v($a), v($$a), v($arr[0]));');
+eval('var_dump(k(id(1)), k($o->get()), k(O::make()), k($a));');
+eval('var_dump(m($o->get()), m(id(1)), m(O::make()));');
+?>
+--EXPECT--
+bool(true)
+bool(true)
+bool(true)
+bool(true)
+bool(true)
+bool(true)
+bool(true)
+string(6) "string"
+string(2) "no"
+string(2) "no"
+string(3) "int"
+string(2) "no"
+string(2) "no"
+string(5) "float"
+string(2) "no"
+string(2) "no"
+string(5) "const"
+string(2) "no"
+string(2) "no"
+string(3) "var"
+string(2) "no"
+string(2) "no"
+string(4) "call"
+string(4) "call"
+string(4) "call"
+string(2) "no"
+string(11) "method_call"
+string(2) "no"
+string(2) "no"
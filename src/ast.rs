@@ -1,32 +1,100 @@
 use ::std::boxed::Box;
-use ::std::collections::HashMap;
-use ::std::ffi::CString;
-use ::std::ops::Deref;
-use ::std::os::raw::c_char;
-use ::std::sync::Mutex;
+use ::std::cell::RefCell;
+use ::std::collections::hash_map::DefaultHasher;
+use ::std::fs::OpenOptions;
+use ::std::hash::{Hash, Hasher};
+use ::std::io::Write;
+use ::std::os::raw::{c_char, c_void};
+use callback;
 use php;
 
+/**
+ * Settings from the pphp.* INI directives.
+ */
+#[derive(Clone)]
 pub struct Options {
+    /// pphp.enable: whether rules are applied at all.
+    pub enabled: bool,
+    /// pphp.debug_trace: whether rule matches are traced.
     pub debug_trace: bool,
+    /// pphp.trace_output: where traces are written; `stdout`, `stderr` or
+    /// the path of a file to append to.
+    pub trace_output: String,
+    /// pphp.disabled_rules: the names of rules which are off unless they're
+    /// enabled with pphp_enable_rule.
+    pub disabled_rules: Vec<String>,
+    /// pphp.optimization_level: built-in rules only run at or above their
+    /// level; see rules::RuleOptions.
+    pub optimization_level: u8,
+    /// pphp.unroll_limit: the most iterations a loop can have to be
+    /// unrolled by rules::loop_unroll.
+    pub unroll_limit: u32,
 }
 
 impl Options {
     pub fn new() -> Self {
         Options {
+            enabled: true,
             debug_trace: false,
+            trace_output: "stdout".to_string(),
+            disabled_rules: Vec::new(),
+            optimization_level: 2,
+            unroll_limit: 8,
         }
     }
 }
 
-lazy_static! {
-    pub static ref OPTIONS: Mutex<Options> = {
-        Mutex::new(Options::new())
-    };
+thread_local! {
+    /// Kept per thread, since the directives can be changed for a request
+    /// with ini_set(). PHP calls the INI handlers again on each new thread,
+    /// so every thread starts out with the configured values.
+    static OPTIONS: RefCell<Options> = RefCell::new(Options::new());
+}
+
+/**
+ * A copy of the current thread's options.
+ */
+pub fn options() -> Options {
+    OPTIONS.with(|options| options.borrow().clone())
+}
+
+/**
+ * Change the current thread's options.
+ */
+pub fn update_options<F>(f: F) where F: FnOnce(&mut Options) {
+    OPTIONS.with(|options| f(&mut options.borrow_mut()))
 }
 
 pub fn set_debug_trace(enabled: bool) {
-    let mut options = OPTIONS.lock().unwrap();
-    options.debug_trace = enabled;
+    update_options(|options| options.debug_trace = enabled);
+}
+
+/**
+ * Write a line of debug trace output to pphp.trace_output.
+ */
+pub fn trace(line: &str) {
+    let output = options().trace_output;
+    match output.as_str() {
+        "" | "stdout" => println!("{}", line),
+        "stderr" => eprintln!("{}", line),
+        path => {
+            let written = OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(e) = written {
+                callback::warn(&format!("pphp: couldn't write trace output to {}: {}", path, e));
+            }
+        }
+    }
+}
+
+/**
+ * Like println!, but writes to pphp.trace_output.
+ */
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::ast::trace(&format!($($arg)*))
+    };
 }
 
 /**
@@ -80,79 +148,166 @@ impl ZendAstPtr {
      */
     pub fn replace(&mut self, new: ZendAst) {
         self.dirty = true;
-        unsafe {
-            ::std::ptr::replace(self.ptr, new);
+        let old = unsafe {
+            ::std::ptr::replace(self.ptr, new)
         };
-    }
-
-    pub fn bind_sub_replace(&mut self, bindings: &Bindings) {
-        match is_bind_param(unsafe {*(self.ptr)}) {
-            Some(var) => {
-                self.replace(bindings.get(&var).unwrap().clone());
+        let ptr = self.ptr;
+        JOURNAL.with(|journal| {
+            if let Some(ref mut changes) = *journal.borrow_mut() {
+                changes.push((ptr, old));
             }
-            _ => {}
-        }
+        });
     }
 
     pub fn set_dirty(&mut self, dirty: bool) {
         self.dirty = dirty;
     }
 }
+thread_local! {
+    /// The slots changed by ZendAstPtr::replace and their previous contents,
+    /// while changes are being recorded by undo_on_panic.
+    static JOURNAL: RefCell<Option<Vec<(*mut ZendAst, ZendAst)>>> = RefCell::new(None);
+}
+
+/**
+ * Stops recording changes when dropped, undoing them if it's dropped by a
+ * panic.
+ */
+struct Journal;
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let changes = JOURNAL.with(|journal| journal.borrow_mut().take()).unwrap_or_default();
+        if ::std::thread::panicking() {
+            // replaced nodes are never freed, so the old pointers are still
+            // valid
+            for (ptr, old) in changes.into_iter().rev() {
+                unsafe { *ptr = old };
+            }
+        }
+    }
+}
+
+/**
+ * Call `f`, recording every change it makes to an AST through
+ * ZendAstPtr::replace. If it panics, the changes are undone before the panic
+ * continues, leaving the AST as it was.
+ */
+pub fn undo_on_panic<F, R>(f: F) -> R where F: FnOnce() -> R {
+    JOURNAL.with(|journal| *journal.borrow_mut() = Some(Vec::new()));
+    let _journal = Journal;
+    f()
+}
+
 impl From<ZendAstPtr> for ZendAst {
     fn from(ptr: ZendAstPtr) -> Self {
         ptr.deref()
     }
 }
 
-pub type Bindings = HashMap<String, ZendAst>;
-
 extern "C" {
-    fn get_ast(code: *const c_char) -> ZendAst;
+    fn get_ast(
+        code: *const c_char,
+        code_len: usize,
+        arena: *mut *mut c_void,
+        error: *mut *mut php::zend_string,
+        error_line: *mut u32
+    ) -> ZendAst;
+    fn string_release(zstr: *mut php::zend_string);
+    fn free_ast(zast: ZendAst, arena: *mut c_void);
     fn ast_is_list(zast: ZendAst) -> bool;
     fn ast_is_decl(zast: ZendAst) -> bool;
     fn ast_num_children(zast: ZendAst) -> u32;
-    fn ast_size(zast: ZendAst) -> u32;
     fn ast_zval(zast: ZendAst) -> *mut php::zval;
-    fn zval_string(zval: *mut php::zval) -> *mut php::zend_string;
     fn var_export(zval: *mut php::zval) -> *mut php::zend_string;
+    fn zval_type(zval: *mut php::zval) -> u8;
+    fn zval_long(zval: *mut php::zval) -> php::zend_long;
+    fn zval_double(zval: *mut php::zval) -> f64;
+    fn zval_str(zval: *mut php::zval) -> *mut php::zend_string;
+    fn ast_lineno(zast: ZendAst) -> u32;
+    fn ast_copy_zval(zast: ZendAst) -> ZendAst;
+    fn ast_copy_decl(zast: ZendAst, c0: ZendAst, c1: ZendAst, c2: ZendAst, c3: ZendAst) -> ZendAst;
+}
+
+/**
+ * A parsed AST which owns the arena its nodes were allocated in. The AST is
+ * destroyed when this is dropped, so nodes from it can't be kept around or
+ * inserted into another tree.
+ */
+pub struct ParsedAst {
+    pub root: ZendAst,
+    arena: *mut c_void,
+}
+
+impl Drop for ParsedAst {
+    fn drop(&mut self) {
+        unsafe { free_ast(self.root, self.arena) };
+    }
+}
+
+/**
+ * The error reported by PHP's parser for invalid code.
+ */
+#[derive(Clone, Debug)]
+pub struct SyntaxError {
+    pub message: String,
+    pub line: u32,
+}
+
+impl ::std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{} on line {}", self.message, self.line)
+    }
 }
 
 /**
  * Parse a string of PHP code into a ZendAst struct. The string should begin
  * with a <?php tag; otherwise it'll parse as an echo statement.
  *
- * Returns None if the parse failed.
+ * Returns the parser's error if the parse failed.
  */
-pub fn parse(code: &str) -> Option<ZendAst> {
-    let cstr = CString::new(code).unwrap();
+pub fn parse(code: &str) -> Result<ParsedAst, SyntaxError> {
     unsafe {
-        let cp = cstr.as_ptr();
-        let ast = get_ast(cp);
+        let mut arena: *mut c_void = ::std::ptr::null_mut();
+        let mut error: *mut php::zend_string = ::std::ptr::null_mut();
+        let mut error_line: u32 = 0;
+        let ast = get_ast(code.as_ptr() as *const c_char, code.len(), &mut arena, &mut error, &mut error_line);
         if ast.is_null() {
-            None
+            let message = if error.is_null() {
+                "syntax error".to_string()
+            } else {
+                let message = String::from_utf8_lossy(zend_str_bytes(error)).into_owned();
+                string_release(error);
+                message
+            };
+            Err(SyntaxError {
+                message: message,
+                line: error_line,
+            })
         } else {
-            Some(ast)
+            Ok(ParsedAst {
+                root: ast,
+                arena: arena,
+            })
         }
     }
 }
 
 /**
- * A convenience function for internal PHP which is known to parse
- * successfully. Adds the opening <?php tag automatically.
+ * A convenience function for parsing patterns. Adds the opening <?php tag
+ * automatically, on the same line so line numbers match the pattern's.
  */
-pub fn parse_pattern(code: &str) -> Option<ZendAst> {
+pub fn parse_pattern(code: &str) -> Result<ParsedAst, SyntaxError> {
     let node = parse(&("<?php ".to_string() + code));
     node
 }
 
 /**
- * Given an AST node, returns a pointer to a pointer for each of the node's
- * children. These pointers can be modified to modify the AST in place.
+ * Returns a pointer to each of a node's child slots, including empty ones.
  */
-pub fn get_children(zast: ZendAst) -> Vec<ZendAstPtr> {
-    let mut children = Vec::new();
-    let mut child: *mut ZendAst;
-    let mut count: u32 = 0;
+fn child_slots(zast: ZendAst) -> Vec<*mut ZendAst> {
+    let child: *mut ZendAst;
+    let count: u32;
     unsafe {
         if ast_is_decl(zast) {
             let decl = zast as ZendAstDecl;
@@ -170,59 +325,123 @@ pub fn get_children(zast: ZendAst) -> Vec<ZendAstPtr> {
             count = ast_num_children(zast);
             child = &mut ((*zast).child[0]) as *mut ZendAst;
         }
-        for i in 0 .. count {
-            let child_ptr = child.offset(i as isize);
-            if !(*child_ptr).is_null() {
-                children.push(ZendAstPtr::new(child_ptr));
-            }
-        }
+        (0 .. count).map(|i| child.offset(i as isize)).collect()
     }
-    children
 }
 
 /**
- * Return an owned String from a zend_string.
+ * Given an AST node, returns a pointer to a pointer for each of the node's
+ * children. These pointers can be modified to modify the AST in place.
  */
-fn zend_str_val<'a>(mut zstr: php::zend_string) -> String {
-    let len = zstr.len;
-    let char_ptr = &mut (zstr.val[0]) as *mut _;
-    unsafe {
-        let cstr = ::std::ffi::CStr::from_ptr(char_ptr);
-        cstr.to_str().unwrap().to_string()
-    }
+pub fn get_children(zast: ZendAst) -> Vec<ZendAstPtr> {
+    child_slots(zast).into_iter()
+        .filter(|child_ptr| unsafe { !(**child_ptr).is_null() })
+        .map(ZendAstPtr::new)
+        .collect()
 }
 
 /**
- * If this AST node matches the form PPHP::$variableName, returns
- * Some(variableName); otherwise returns None.
- *
- * TODO: implement constraints
+ * Like get_children, but pairs each child with its position among all of the
+ * node's children, including NULL ones.
  */
-pub fn is_bind_param(zast: ZendAst) -> Option<String> {
-    let deref: php::zend_ast = unsafe { *zast };
-    if deref.kind == php::_zend_ast_kind::ZEND_AST_STATIC_PROP as u16 {
-        let children = get_children(zast);
-        if children.len() != 2 {
-            return None;
-        }
-        let clsNode = &children[0];
-        let varNameNode = &children[1];
-        unsafe {
-            if (*clsNode.deref()).kind != php::_zend_ast_kind::ZEND_AST_ZVAL as u16 {
-                return None;
+pub fn get_indexed_children(zast: ZendAst) -> Vec<(usize, ZendAstPtr)> {
+    child_slots(zast).into_iter()
+        .enumerate()
+        .filter(|&(_, child_ptr)| unsafe { !(*child_ptr).is_null() })
+        .map(|(index, child_ptr)| (index, ZendAstPtr::new(child_ptr)))
+        .collect()
+}
+
+/**
+ * Returns all of a node's children, including NULL ones, so that positions
+ * are preserved (e.g. an `if` without an `else`.)
+ */
+pub fn get_raw_children(zast: ZendAst) -> Vec<ZendAst> {
+    child_slots(zast).into_iter().map(|child_ptr| unsafe { *child_ptr }).collect()
+}
+
+/**
+ * Borrow the contents of a zend_string as bytes. PHP strings can contain any
+ * bytes, including NULs, so this uses the string's length rather than
+ * looking for a terminator, and makes no assumptions about the encoding.
+ */
+pub unsafe fn zend_str_bytes<'a>(zstr: *mut php::zend_string) -> &'a [u8] {
+    ::std::slice::from_raw_parts((*zstr).val.as_ptr() as *const u8, (*zstr).len)
+}
+
+/**
+ * Compare two names as PHP compares function and class names: ignoring the
+ * case of ASCII letters, and comparing any other bytes exactly.
+ */
+pub fn names_equal(a: &[u8], b: &[u8]) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/**
+ * Make a byte string printable for debug output: valid UTF-8 is kept as is,
+ * while ASCII control characters and bytes which aren't part of a UTF-8
+ * character are written as escapes like `\x00`.
+ */
+pub fn escape_bytes(mut bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    loop {
+        let (valid, rest) = match ::std::str::from_utf8(bytes) {
+            Ok(s) => (s, &[][..]),
+            Err(e) => {
+                let valid = e.valid_up_to();
+                (unsafe { ::std::str::from_utf8_unchecked(&bytes[..valid]) }, &bytes[valid..])
             }
-            if (*varNameNode.deref()).kind != php::_zend_ast_kind::ZEND_AST_ZVAL as u16 {
-                return None;
+        };
+        for c in valid.chars() {
+            if (c as u32) < 0x20 || c == '\x7f' {
+                escaped.push_str(&format!("\\x{:02x}", c as u32));
+            } else {
+                escaped.push(c);
+            }
+        }
+        match rest.split_first() {
+            Some((byte, rest)) => {
+                escaped.push_str(&format!("\\x{:02x}", byte));
+                bytes = rest;
             }
-            let cls = zval_string(ast_zval(clsNode.deref()));
-            let varName = zval_string(ast_zval(varNameNode.deref()));
-            if zend_str_val(*cls) == "PPHP" {
-                // found it!
-                return Some(zend_str_val(*varName));
+            None => return escaped,
+        }
+    }
+}
+
+/**
+ * Returns true if two ASTs are structurally identical.
+ */
+pub fn nodes_equal(a: ZendAst, b: ZendAst) -> bool {
+    let (a, b) = (unwrap_all(a), unwrap_all(b));
+    unsafe {
+        if (*a).kind != (*b).kind || (*a).attr != (*b).attr {
+            return false;
+        }
+        if (*a).kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 {
+            return php::zend_is_identical(ast_zval(a), ast_zval(b)) != 0;
+        }
+        if ast_is_decl(a) {
+            let (name_a, name_b) = ((*(a as ZendAstDecl)).name, (*(b as ZendAstDecl)).name);
+            if !names_equal(zend_str_bytes(name_a), zend_str_bytes(name_b)) {
+                return false;
             }
         }
     }
-    None
+    let (children_a, children_b) = if unsafe { ast_is_list(a) } {
+        (get_children(a).iter().map(|c| c.deref()).collect(),
+         get_children(b).iter().map(|c| c.deref()).collect())
+    } else {
+        (get_raw_children(a), get_raw_children(b))
+    };
+    children_a.len() == children_b.len() &&
+        children_a.iter().zip(children_b.iter()).all(|(a, b)| {
+            match (a.is_null(), b.is_null()) {
+                (true, true) => true,
+                (false, false) => nodes_equal(*a, *b),
+                _ => false,
+            }
+        })
 }
 
 /**
@@ -277,62 +496,105 @@ pub fn unwrap_all(zast: ZendAst) -> ZendAst {
 }
 
 /**
- * Returns true if `zast` matches the provided `pattern`. If variable names are
- * encountered, the corresponding AST node will be bound to the variable.
+ * Allocate a new list node of the given kind containing `children`.
  */
-pub fn pattern_match(mut pattern: ZendAst, mut zast: ZendAst, mut bindings: &mut Bindings) -> bool {
-    // unwrap statement lists with exactly 1 child
-    pattern = unwrap_all(pattern);
-    zast = unwrap_all(zast);
-
-    match is_bind_param(pattern) {
-        Some(var) => {
-            if bindings.contains_key(&var) {
-                return pattern_match(bindings.get(&var).unwrap().clone(), zast, &mut bindings);
-            } else {
-                bindings.insert(var, zast);
-                return true;
-            }
+pub fn create_list(kind: php::zend_ast_kind, attr: php::zend_ast_attr, children: &[ZendAst]) -> ZendAst {
+    unsafe {
+        let mut list = php::zend_ast_create_list(0, kind);
+        for child in children {
+            list = php::zend_ast_list_add(list, *child);
         }
-        None => unsafe {
-            if (*pattern).kind == (*zast).kind {
-                // TODO: should attr mismatch matter for all kinds?
-                if (*pattern).attr != (*zast).attr {
-                    return false;
-                }
-                // TODO: decl name binding?
-                // node-specific checks
-                if (*pattern).kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 {
-                    let identical = php::zend_is_identical(ast_zval(pattern), ast_zval(zast));
-                    if identical == 0 {
-                        return false;
-                    }
-                }
-                let children1 = get_children(pattern);
-                let children2 = get_children(zast);
-                if children1.len() != children2.len() {
-                    return false;
-                }
-                for i in 0 .. children1.len() {
-                    if !pattern_match(children1[i].deref(), children2[i].deref(), &mut bindings) {
-                        return false;
-                    }
-                }
+        (*list).attr = attr;
+        list
+    }
+}
+
+/**
+ * Allocate a copy of a single node, with `children` in place of its own.
+ */
+pub fn copy_node(zast: ZendAst, children: &[ZendAst]) -> ZendAst {
+    let child = |i: usize| children.get(i).cloned().unwrap_or(::std::ptr::null_mut());
+    unsafe {
+        let kind = (*zast).kind;
+        if kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 {
+            ast_copy_zval(zast)
+        } else if ast_is_decl(zast) {
+            ast_copy_decl(zast, child(0), child(1), child(2), child(3))
+        } else {
+            let node = if ast_is_list(zast) {
+                create_list(kind, (*zast).attr, children)
             } else {
-                return false;
-            }
+                php::zend_ast_create_ex(kind, (*zast).attr, child(0), child(1), child(2), child(3))
+            };
+            (*node).lineno = ast_lineno(zast);
+            node
         }
     }
-    true
 }
 
 /**
- * Replace all instances of PPHP::$variable nodes with the bound pattern.
+ * Make a deep copy of an AST, so that it can be inserted somewhere else in
+ * the tree. `f` is called on each node before it's copied; if it returns a
+ * node, that's used in place of a copy of the node and its children.
  */
-pub fn bind_sub(ast: ZendAst, bindings: &Bindings) {
-    ast_walk!(ast, |node_ptr: &mut ZendAstPtr| {
-        node_ptr.bind_sub_replace(&bindings);
-    });
+pub fn copy_tree<F>(zast: ZendAst, f: &mut F) -> ZendAst where F: FnMut(ZendAst) -> Option<ZendAst> {
+    if zast.is_null() {
+        return zast;
+    }
+    if let Some(node) = f(zast) {
+        return node;
+    }
+    let children: Vec<ZendAst> = get_raw_children(zast).into_iter()
+        .map(|child| copy_tree(child, f))
+        .collect();
+    copy_node(zast, &children)
+}
+
+/**
+ * Hash the structure of an AST, such that nodes_equal ASTs hash the same.
+ */
+pub fn hash_tree(zast: ZendAst) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_node(zast, &mut hasher);
+    hasher.finish()
+}
+
+fn hash_node<H: Hasher>(zast: ZendAst, state: &mut H) {
+    if zast.is_null() {
+        state.write_u8(0);
+        return;
+    }
+    let zast = unwrap_all(zast);
+    unsafe {
+        (*zast).kind.hash(state);
+        (*zast).attr.hash(state);
+        if (*zast).kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 {
+            let zv = ast_zval(zast);
+            let t = zval_type(zv);
+            t.hash(state);
+            match t as u32 {
+                php::IS_LONG => zval_long(zv).hash(state),
+                php::IS_DOUBLE => zval_double(zv).to_bits().hash(state),
+                php::IS_STRING => zend_str_bytes(zval_str(zv)).hash(state),
+                _ => {}
+            }
+            return;
+        }
+        if ast_is_decl(zast) {
+            zend_str_bytes((*(zast as ZendAstDecl)).name).to_ascii_lowercase().hash(state);
+        }
+    }
+    if unsafe { ast_is_list(zast) } {
+        let children = get_children(zast);
+        children.len().hash(state);
+        for child in children {
+            hash_node(child.deref(), state);
+        }
+    } else {
+        for child in get_raw_children(zast) {
+            hash_node(child, state);
+        }
+    }
 }
 
 pub fn print_node(zast: ZendAst, indentation: usize) {
@@ -342,110 +604,167 @@ pub fn print_node(zast: ZendAst, indentation: usize) {
         }
         None => {}
     }
-    let kind: php::_zend_ast_kind = unsafe {
-        ::std::mem::transmute((*zast).kind as u32)
-    };
-    match is_bind_param(zast) {
-        Some(var) => {
-            println!("{:width$}<${}>", " ", var, width=indentation * 2);
-        }
-        None => {
-            let children = get_children(zast);
-            if kind == php::_zend_ast_kind::ZEND_AST_ZVAL {
-                let zstr = unsafe {
-                    *var_export(ast_zval(zast))
-                };
-                let dump = zend_str_val(zstr);
-                println!("{:width$}ZVAL {} ({} children)", " ", dump, children.len(), width=indentation * 2);
+    let kind = unsafe { (*zast).kind };
+    let children = get_children(zast);
+    if kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 {
+        let dump = unsafe {
+            let zstr = var_export(ast_zval(zast));
+            let dump = escape_bytes(zend_str_bytes(zstr));
+            string_release(zstr);
+            dump
+        };
+        trace!("{:width$}ZVAL {} ({} children)", " ", dump, children.len(), width=indentation * 2);
+    } else {
+        let name = unsafe {
+            if ast_is_decl(zast) {
+                let decl = zast as ZendAstDecl;
+                format!("{} ", escape_bytes(zend_str_bytes((*decl).name)))
             } else {
-                let name = unsafe {
-                    if ast_is_decl(zast) {
-                        let decl = *(zast as ZendAstDecl);
-                        format!("{} ", zend_str_val(*decl.name))
-                    } else {
-                        format!("")
-                    }
-                };
-                let attr = unsafe {
-                    let a = (*zast).attr;
-                    if a == 0 {
-                        format!("")
-                    } else {
-                        format!("0x{:x} ", a)
-                    }
-                };
-                let kind_name = {
-                    let debug_fmt = format!("{:?}", kind);
-                    if &debug_fmt[..9] == "ZEND_AST_" {
-                        (&(format!("{:?}", kind))[9..]).to_string()
-                    } else {
-                        debug_fmt
-                    }
-                };
-                println!("{:width$}{} {}{}({} children)", " ", kind_name, name, attr, children.len(), width=indentation * 2);
+                format!("")
             }
-            for child in children {
-                print_node(child.deref(), indentation + 1);
-            }
-        }
+        };
+        let attr = attr_string(unsafe { (*zast).attr });
+        trace!("{:width$}{} {}{}({} children)", " ", kind_name(kind), name, attr, children.len(), width=indentation * 2);
+    }
+    for child in children {
+        print_node(child.deref(), indentation + 1);
+    }
+}
+
+/**
+ * The kind of a node, as a _zend_ast_kind so that it can be matched on.
+ */
+pub fn kind_of(zast: ZendAst) -> php::_zend_ast_kind {
+    unsafe {
+        ::std::mem::transmute((*zast).kind as u32)
+    }
+}
+
+/**
+ * The name of a zend_ast_kind without its ZEND_AST_ prefix, for debug output.
+ */
+pub fn kind_name(kind: php::zend_ast_kind) -> String {
+    let kind: php::_zend_ast_kind = unsafe {
+        ::std::mem::transmute(kind as u32)
+    };
+    let debug_fmt = format!("{:?}", kind);
+    if &debug_fmt[..9] == "ZEND_AST_" {
+        (&debug_fmt[9..]).to_string()
+    } else {
+        debug_fmt
+    }
+}
+
+pub fn attr_string(attr: php::zend_ast_attr) -> String {
+    if attr == 0 {
+        format!("")
+    } else {
+        format!("0x{:x} ", attr)
     }
 }
 
 /**
  * Map an AST substitution rule over an AST tree, replacing any instances in
- * $ast which match.
+ * $ast which match. Both patterns are compiled pattern::Patterns, and $ctx is
+ * the PhpContext passed to the rule, which names in $ast are resolved in.
  *
  * The following special patterns are supported:
  *
  * Static variables in the form PPHP::$myVariable will bind to any expression
  * and can be referenced in the replacement pattern.
+ *
+ * A variable can be constrained to certain kinds of expression by appending
+ * the constraint to its name, e.g. PPHP::${'myVariable:string'}; see
+ * constraint::Constraint.
+ *
+ * Identifier variables, in the form PPHP__myVariable, bind the names of
+ * functions, methods, classes, properties and declarations wherever PHP
+ * expects a name rather than an expression, e.g. `PPHP__fn(PPHP::$arg)`.
+ *
+ * Variadic variables, written as ...PPHP::$myVariable in argument lists or
+ * PPHP::${'...myVariable'} elsewhere, bind zero or more consecutive elements
+ * of a list, such as the rest of an argument list or block of statements.
+ *
+ * Function, class and constant names in the search pattern are taken to be in
+ * the global namespace, and match any spelling of the same name in $ast
+ * (e.g. `is_a` matches `\is_a`, or `is_a` in a namespace without its own
+ * is_a function.) Names in the replacement are fully qualified.
+ *
+ * An optional fifth argument is a guard, called with the bindings after a
+ * successful match; the substitution is only made if it returns true.
  */
 #[macro_export]
 macro_rules! map_sub {
-    ($patternSearch:expr, $patternReplace:expr, $ast:expr) => {
-        let mut bindings = ast::Bindings::new();
-        map_fun!(__impl, bindings, $patternSearch, $ast, {
-            let replace = ast::parse_pattern($patternReplace).unwrap();
-            ast::bind_sub(replace, &bindings);
-            $ast.replace($crate::ast::unwrap_all(replace));
+    ($patternSearch:expr, $patternReplace:expr, $ast:expr, $ctx:expr) => {
+        map_sub!($patternSearch, $patternReplace, $ast, $ctx, |_| true);
+    };
+
+    ($patternSearch:expr, $patternReplace:expr, $ast:expr, $ctx:expr, $guard:expr) => {
+        let mut bindings = $crate::pattern::Bindings::new();
+        map_fun!(__impl, bindings, $patternSearch, $ast, $ctx, $guard, {
+            let replace: &$crate::pattern::Pattern = $patternReplace;
+            if let Some(replace) = replace.instantiate(&bindings, $ast.deref()) {
+                $ast.replace($crate::ast::unwrap_all(replace));
+            }
         });
     };
 }
 
 /**
  * Like map_sub, but instead of a simple substitution, calls the provided
- * function on a match. The function should modify the AST in place and return
- * true if it was modified and false otherwise.
+ * function on a match. The function should modify the AST in place and call
+ * `$ast.set_dirty(true)` if it was modified.
  *
- * $searchPattern can contain variables to be bound, as in map_sub.
+ * $searchPattern can contain variables to be bound, as in map_sub. To access
+ * the bindings from the function, name them before the function body:
+ *
+ *     map_fun!(&self.pattern, ast, ctx, bindings, { ... });
+ *
+ * As with map_sub, a guard can be given after the bindings' name; the
+ * function is only called if it returns true.
  */
 #[macro_export]
 macro_rules! map_fun {
-    ($patternSearch:expr, $ast:expr, $fun:tt) => {
-        {
-            let mut bindings = ast::Bindings::new();
-            map_fun!(__impl, bindings, $patternSearch, $ast, $fun);
-        }
-    };
-
-    (__impl, $bindings:ident, $patternSearch:expr, $ast:expr, $fun:tt) => {
+    (__impl, $bindings:ident, $patternSearch:expr, $ast:expr, $ctx:expr, $guard:expr, $fun:tt) => {
         {
-            let pattern = ast::parse_pattern($patternSearch).unwrap();
-            if ast::pattern_match(pattern, $ast.deref(), &mut $bindings) {
-                let options = ast::OPTIONS.lock().unwrap();
-                if options.debug_trace {
-                    println!("** PPHP rule match **");
-                    println!("==> matched pattern:");
-                    $crate::ast::print_node(pattern, 2);
-                    println!("==> original AST:");
+            let pattern: &$crate::pattern::Pattern = $patternSearch;
+            if pattern.matches($ast.deref(), &mut $bindings, $ctx) && ($guard)(&$bindings) {
+                // not held while $fun runs, since it may call back into PHP
+                let debug_trace = $crate::ast::options().debug_trace;
+                if debug_trace {
+                    trace!("** PPHP rule match **");
+                    trace!("==> matched pattern:");
+                    pattern.print(2);
+                    trace!("==> original AST:");
                     $crate::ast::print_node($ast.deref(), 2);
                 }
                 $fun
-                if options.debug_trace {
-                    println!("==> new AST:");
+                if debug_trace {
+                    trace!("==> new AST:");
                     $crate::ast::print_node($ast.deref(), 2);
                 }
             }
         }
     };
+
+    ($patternSearch:expr, $ast:expr, $ctx:expr, $fun:tt) => {
+        {
+            let mut bindings = $crate::pattern::Bindings::new();
+            map_fun!(__impl, bindings, $patternSearch, $ast, $ctx, |_| true, $fun);
+        }
+    };
+
+    ($patternSearch:expr, $ast:expr, $ctx:expr, $bindings:ident, $fun:tt) => {
+        {
+            let mut $bindings = $crate::pattern::Bindings::new();
+            map_fun!(__impl, $bindings, $patternSearch, $ast, $ctx, |_| true, $fun);
+        }
+    };
+
+    ($patternSearch:expr, $ast:expr, $ctx:expr, $bindings:ident, $guard:expr, $fun:tt) => {
+        {
+            let mut $bindings = $crate::pattern::Bindings::new();
+            map_fun!(__impl, $bindings, $patternSearch, $ast, $ctx, $guard, $fun);
+        }
+    };
 }
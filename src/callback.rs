@@ -0,0 +1,239 @@
+use ::std::cell::Cell;
+use ::std::ffi::CString;
+use ::std::os::raw::{c_char, c_int};
+use ::std::thread::{self, ThreadId};
+use ast::{self, ZendAst};
+use pattern::{Binding, Bindings};
+use php;
+
+extern "C" {
+    fn callable_copy(callable: *mut php::zval) -> *mut php::zval;
+    fn callable_free(callable: *mut php::zval);
+    fn bindings_array_new() -> *mut php::zval;
+    fn bindings_array_add_array(arr: *mut php::zval, name: *const c_char, name_len: usize) -> *mut php::zval;
+    fn bindings_array_free(arr: *mut php::zval);
+    fn bindings_array_add_ast(arr: *mut php::zval, name: *const c_char, name_len: usize, zast: ZendAst);
+    fn bindings_array_add_tree(arr: *mut php::zval, name: *const c_char, name_len: usize, zast: ZendAst);
+    fn call_guard(callable: *mut php::zval, arg: *mut php::zval) -> c_int;
+    fn call_rewrite(
+        callable: *mut php::zval,
+        node: ZendAst,
+        bindings: *mut php::zval,
+        result_ast: *mut ZendAst,
+        result_src: *mut *mut php::zend_string
+    ) -> c_int;
+    fn string_release(zstr: *mut php::zend_string);
+}
+
+/**
+ * The replacement returned by a rewrite callback.
+ */
+pub enum Rewrite {
+    /// PHP source, to be parsed as a pattern with the bindings substituted.
+    Source(String),
+    /// A node built from the array AST returned by the callback.
+    Ast(ZendAst),
+}
+
+/// Returned by call_guard and call_rewrite if the callable bailed out.
+const CALL_BAILOUT: c_int = -2;
+
+thread_local! {
+    /// Counts the requests this thread has finished, so that values from an
+    /// earlier request can be told apart.
+    static REQUEST: Cell<u64> = Cell::new(0);
+
+    /// Set when a callback bails out, e.g. with a fatal error, until the
+    /// bailout is re-raised.
+    static BAILOUT: Cell<bool> = Cell::new(false);
+}
+
+/**
+ * Returns true if a callback has bailed out. No more callbacks are called
+ * until the bailout has been re-raised, once the rules have been released.
+ */
+pub fn bailed_out() -> bool {
+    BAILOUT.with(|bailout| bailout.get())
+}
+
+/**
+ * Clear the bailout flag, returning whether it was set; the caller must then
+ * re-raise the bailout with zend_bailout().
+ */
+pub fn take_bailout() -> bool {
+    BAILOUT.with(|bailout| bailout.replace(false))
+}
+
+/**
+ * Called at the end of each request, once everything holding a PhpCallable
+ * from it has been dropped.
+ */
+pub fn end_request() {
+    REQUEST.with(|request| request.set(request.get() + 1));
+}
+
+/**
+ * A callable passed in from PHP, such as the guard argument to
+ * pphp_add_rule. Holds a reference to the callable until dropped.
+ *
+ * The callable is allocated on the request's heap, so it's only valid on the
+ * thread and in the request it was created in. Rules holding one are kept
+ * per request (see rules::add_rule), but in case one outlives its request,
+ * it's never called or released after that.
+ */
+#[derive(Debug)]
+pub struct PhpCallable {
+    zval: *mut php::zval,
+    thread: ThreadId,
+    request: u64,
+}
+
+// the callable is only touched from the thread and request it belongs to
+unsafe impl Send for PhpCallable {}
+unsafe impl Sync for PhpCallable {}
+
+impl PhpCallable {
+    pub fn new(callable: *mut php::zval) -> Self {
+        PhpCallable {
+            zval: unsafe { callable_copy(callable) },
+            thread: thread::current().id(),
+            request: REQUEST.with(|request| request.get()),
+        }
+    }
+
+    /**
+     * Returns true if this is the thread and request the callable was
+     * created in.
+     */
+    fn is_current(&self) -> bool {
+        self.thread == thread::current().id() && self.request == REQUEST.with(|request| request.get())
+    }
+
+    /**
+     * Raise a warning and return false if the callable can't be called.
+     */
+    fn check_current(&self) -> bool {
+        if bailed_out() {
+            return false;
+        }
+        let current = self.is_current();
+        if !current {
+            warn("pphp: a callback from another request can't be called");
+        }
+        current
+    }
+
+    /**
+     * Call this callable as a rule guard, passing it an array mapping each
+     * bound variable name to the PHP source of the node it matched. Returns
+     * true if the guard accepts the match.
+     *
+     * A guard that can't be called, throws an exception or bails out rejects
+     * the match.
+     */
+    pub fn call_guard(&self, bindings: &Bindings) -> bool {
+        if !self.check_current() {
+            return false;
+        }
+        unsafe {
+            let arr = export_bindings(bindings, false);
+            let result = call_guard(self.zval, arr);
+            bindings_array_free(arr);
+            if result == CALL_BAILOUT {
+                BAILOUT.with(|bailout| bailout.set(true));
+            } else if result < 0 {
+                warn("pphp: rule guard failed; match rejected");
+            }
+            result > 0
+        }
+    }
+
+    /**
+     * Call this callable as a rewrite rule, passing it the matched node and
+     * an array mapping each bound variable name to its node, both in array
+     * AST form. Returns the replacement, or None if the callback declined to
+     * rewrite the node (by returning null or false) or failed.
+     */
+    pub fn call_rewrite(&self, node: ZendAst, bindings: &Bindings) -> Option<Rewrite> {
+        if !self.check_current() {
+            return None;
+        }
+        unsafe {
+            let arr = export_bindings(bindings, true);
+            let mut result_ast: ZendAst = ::std::ptr::null_mut();
+            let mut result_src: *mut php::zend_string = ::std::ptr::null_mut();
+            let result = call_rewrite(self.zval, node, arr, &mut result_ast, &mut result_src);
+            bindings_array_free(arr);
+            if result == CALL_BAILOUT {
+                BAILOUT.with(|bailout| bailout.set(true));
+                None
+            } else if result < 0 {
+                warn("pphp: rewrite callback failed or returned an invalid AST; node left unchanged");
+                None
+            } else if result == 0 {
+                None
+            } else if !result_src.is_null() {
+                let src = String::from_utf8(ast::zend_str_bytes(result_src).to_vec());
+                string_release(result_src);
+                match src {
+                    Ok(src) => Some(Rewrite::Source(src)),
+                    Err(_) => {
+                        warn("pphp: rewrite callback returned code which isn't valid UTF-8; node left unchanged");
+                        None
+                    }
+                }
+            } else {
+                Some(Rewrite::Ast(result_ast))
+            }
+        }
+    }
+}
+
+impl Drop for PhpCallable {
+    fn drop(&mut self) {
+        // once its request has ended, the callable was freed with the heap
+        if self.is_current() {
+            unsafe { callable_free(self.zval) };
+        }
+    }
+}
+
+/**
+ * Build a PHP array mapping variable names to their bound nodes, either as
+ * PHP source or, if `as_tree` is set, in array AST form. Variadic bindings
+ * map to a list of nodes.
+ */
+unsafe fn export_bindings(bindings: &Bindings, as_tree: bool) -> *mut php::zval {
+    let arr = bindings_array_new();
+    let add = |arr: *mut php::zval, name: *const c_char, name_len: usize, zast: ZendAst| {
+        if as_tree {
+            bindings_array_add_tree(arr, name, name_len, zast);
+        } else {
+            bindings_array_add_ast(arr, name, name_len, zast);
+        }
+    };
+    for (name, binding) in bindings.iter() {
+        let name_ptr = name.as_ptr() as *const c_char;
+        match *binding {
+            Binding::Node(zast) => add(arr, name_ptr, name.len(), zast),
+            Binding::List(ref nodes) => {
+                let list = bindings_array_add_array(arr, name_ptr, name.len());
+                for zast in nodes {
+                    add(list, ::std::ptr::null(), 0, *zast);
+                }
+            }
+        }
+    }
+    arr
+}
+
+/**
+ * Raise a PHP warning.
+ */
+pub fn warn(msg: &str) {
+    // messages can include user patterns, which may contain NUL bytes or %
+    let msg = CString::new(msg.replace('\0', "\\0")).unwrap_or_default();
+    unsafe {
+        php::zend_error(php::E_WARNING as c_int, b"%s\0".as_ptr() as *const c_char, msg.as_ptr());
+    }
+}
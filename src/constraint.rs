@@ -0,0 +1,170 @@
+use ast::{self, ZendAst};
+use php;
+use purity;
+
+extern "C" {
+    fn ast_zval(zast: ZendAst) -> *mut php::zval;
+    fn zval_type(zval: *mut php::zval) -> u8;
+}
+
+/**
+ * A restriction on which AST nodes a PPHP::$variable binding will accept.
+ *
+ * Constraints are written after the variable name, separated by a colon, using
+ * PHP's variable-variable syntax: `PPHP::${'cls:string'}`. Several
+ * constraints can be combined with `|` (`PPHP::${'n:int|float'}`), in which
+ * case the node has to satisfy any one of them.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Constraint {
+    /// A string literal.
+    String,
+    /// An integer literal.
+    Int,
+    /// A floating point literal.
+    Float,
+    /// An integer or floating point literal.
+    Number,
+    /// Any literal value (ZEND_AST_ZVAL).
+    Literal,
+    /// A constant fetch such as `FOO` or `\Bar\BAZ`.
+    Const,
+    /// A plain variable such as `$foo`.
+    Var,
+    /// A function, method or static method call.
+    Call,
+    /// An expression without side effects; see purity::is_pure.
+    Pure,
+    /// Any node of the given zend_ast_kind.
+    Kind(php::_zend_ast_kind),
+}
+
+impl Constraint {
+    /**
+     * Parse a single constraint name. Besides the named constraints, any
+     * zend_ast_kind is accepted either in full (`ZEND_AST_METHOD_CALL`) or
+     * as a lowercase suffix (`method_call`).
+     */
+    pub fn parse(name: &str) -> Option<Constraint> {
+        match name {
+            "string" => Some(Constraint::String),
+            "int" => Some(Constraint::Int),
+            "float" => Some(Constraint::Float),
+            "number" => Some(Constraint::Number),
+            "literal" => Some(Constraint::Literal),
+            "const" => Some(Constraint::Const),
+            "var" => Some(Constraint::Var),
+            "call" => Some(Constraint::Call),
+            "pure" => Some(Constraint::Pure),
+            _ => ast_kind_from_name(name).map(Constraint::Kind),
+        }
+    }
+
+    /**
+     * Returns true if `zast` satisfies this constraint.
+     */
+    pub fn accepts(&self, zast: ZendAst) -> bool {
+        let kind = unsafe { (*zast).kind };
+        let is_kind = |k: php::_zend_ast_kind| kind == k as u16;
+        let zval_is = |types: &[u32]| {
+            is_kind(php::_zend_ast_kind::ZEND_AST_ZVAL) && {
+                let t = unsafe { zval_type(ast_zval(zast)) } as u32;
+                types.contains(&t)
+            }
+        };
+        match *self {
+            Constraint::String => zval_is(&[php::IS_STRING]),
+            Constraint::Int => zval_is(&[php::IS_LONG]),
+            Constraint::Float => zval_is(&[php::IS_DOUBLE]),
+            Constraint::Number => zval_is(&[php::IS_LONG, php::IS_DOUBLE]),
+            Constraint::Literal => is_kind(php::_zend_ast_kind::ZEND_AST_ZVAL),
+            Constraint::Const => is_kind(php::_zend_ast_kind::ZEND_AST_CONST),
+            Constraint::Var => {
+                // $$foo is also a ZEND_AST_VAR, but its child isn't a name
+                is_kind(php::_zend_ast_kind::ZEND_AST_VAR) &&
+                    ast::get_children(zast).iter().all(|child| {
+                        unsafe { (*child.deref()).kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 }
+                    })
+            }
+            Constraint::Call => {
+                is_kind(php::_zend_ast_kind::ZEND_AST_CALL) ||
+                    is_kind(php::_zend_ast_kind::ZEND_AST_METHOD_CALL) ||
+                    is_kind(php::_zend_ast_kind::ZEND_AST_STATIC_CALL)
+            }
+            Constraint::Pure => purity::is_pure(zast),
+            Constraint::Kind(k) => is_kind(k),
+        }
+    }
+}
+
+impl ::std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            Constraint::String => write!(f, "string"),
+            Constraint::Int => write!(f, "int"),
+            Constraint::Float => write!(f, "float"),
+            Constraint::Number => write!(f, "number"),
+            Constraint::Literal => write!(f, "literal"),
+            Constraint::Const => write!(f, "const"),
+            Constraint::Var => write!(f, "var"),
+            Constraint::Call => write!(f, "call"),
+            Constraint::Pure => write!(f, "pure"),
+            Constraint::Kind(k) => write!(f, "{:?}", k),
+        }
+    }
+}
+
+/**
+ * Parse a `|` separated list of constraints, as found after the colon in
+ * `PPHP::${'name:string|int'}`.
+ */
+pub fn parse_constraints(spec: &str) -> Option<Vec<Constraint>> {
+    spec.split('|').map(|name| Constraint::parse(name.trim())).collect()
+}
+
+/**
+ * Look up a zend_ast_kind by name, e.g. "ZEND_AST_CALL" or "call".
+ */
+pub fn ast_kind_from_name(name: &str) -> Option<php::_zend_ast_kind> {
+    let full_name = if name.starts_with("ZEND_AST_") {
+        name.to_string()
+    } else {
+        format!("ZEND_AST_{}", name.to_uppercase())
+    };
+    AST_KINDS.iter().cloned().find(|kind| format!("{:?}", kind) == full_name)
+}
+
+pub const AST_KINDS: [php::_zend_ast_kind; 98] = {
+    use php::_zend_ast_kind::*;
+    [
+        ZEND_AST_ZVAL, ZEND_AST_ZNODE, ZEND_AST_FUNC_DECL, ZEND_AST_CLOSURE,
+        ZEND_AST_METHOD, ZEND_AST_CLASS, ZEND_AST_ARG_LIST, ZEND_AST_LIST,
+        ZEND_AST_ARRAY, ZEND_AST_ENCAPS_LIST, ZEND_AST_EXPR_LIST,
+        ZEND_AST_STMT_LIST, ZEND_AST_IF, ZEND_AST_SWITCH_LIST,
+        ZEND_AST_CATCH_LIST, ZEND_AST_PARAM_LIST, ZEND_AST_CLOSURE_USES,
+        ZEND_AST_PROP_DECL, ZEND_AST_CONST_DECL, ZEND_AST_CLASS_CONST_DECL,
+        ZEND_AST_NAME_LIST, ZEND_AST_TRAIT_ADAPTATIONS, ZEND_AST_USE,
+        ZEND_AST_MAGIC_CONST, ZEND_AST_TYPE, ZEND_AST_VAR, ZEND_AST_CONST,
+        ZEND_AST_UNPACK, ZEND_AST_UNARY_PLUS, ZEND_AST_UNARY_MINUS,
+        ZEND_AST_CAST, ZEND_AST_EMPTY, ZEND_AST_ISSET, ZEND_AST_SILENCE,
+        ZEND_AST_SHELL_EXEC, ZEND_AST_CLONE, ZEND_AST_EXIT, ZEND_AST_PRINT,
+        ZEND_AST_INCLUDE_OR_EVAL, ZEND_AST_UNARY_OP, ZEND_AST_PRE_INC,
+        ZEND_AST_PRE_DEC, ZEND_AST_POST_INC, ZEND_AST_POST_DEC,
+        ZEND_AST_YIELD_FROM, ZEND_AST_GLOBAL, ZEND_AST_UNSET, ZEND_AST_RETURN,
+        ZEND_AST_LABEL, ZEND_AST_REF, ZEND_AST_HALT_COMPILER, ZEND_AST_ECHO,
+        ZEND_AST_THROW, ZEND_AST_GOTO, ZEND_AST_BREAK, ZEND_AST_CONTINUE,
+        ZEND_AST_DIM, ZEND_AST_PROP, ZEND_AST_STATIC_PROP, ZEND_AST_CALL,
+        ZEND_AST_CLASS_CONST, ZEND_AST_ASSIGN, ZEND_AST_ASSIGN_REF,
+        ZEND_AST_ASSIGN_OP, ZEND_AST_BINARY_OP, ZEND_AST_GREATER,
+        ZEND_AST_GREATER_EQUAL, ZEND_AST_AND, ZEND_AST_OR, ZEND_AST_ARRAY_ELEM,
+        ZEND_AST_NEW, ZEND_AST_INSTANCEOF, ZEND_AST_YIELD, ZEND_AST_COALESCE,
+        ZEND_AST_STATIC, ZEND_AST_WHILE, ZEND_AST_DO_WHILE, ZEND_AST_IF_ELEM,
+        ZEND_AST_SWITCH, ZEND_AST_SWITCH_CASE, ZEND_AST_DECLARE,
+        ZEND_AST_CONST_ELEM, ZEND_AST_USE_TRAIT, ZEND_AST_TRAIT_PRECEDENCE,
+        ZEND_AST_METHOD_REFERENCE, ZEND_AST_NAMESPACE, ZEND_AST_USE_ELEM,
+        ZEND_AST_TRAIT_ALIAS, ZEND_AST_GROUP_USE, ZEND_AST_METHOD_CALL,
+        ZEND_AST_STATIC_CALL, ZEND_AST_CONDITIONAL, ZEND_AST_TRY,
+        ZEND_AST_CATCH, ZEND_AST_PARAM, ZEND_AST_PROP_ELEM, ZEND_AST_FOR,
+        ZEND_AST_FOREACH,
+    ]
+};
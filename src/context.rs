@@ -1,7 +1,595 @@
-// TODO
-pub struct PhpContext;
+use ::std::collections::HashMap;
+use ast::{self, ZendAst, ZendAstPtr};
+use pattern::Value;
+use php;
+use php::_zend_ast_kind::*;
+
+extern "C" {
+    fn ast_is_decl(zast: ZendAst) -> bool;
+    fn use_type(attr: php::zend_ast_attr) -> u32;
+}
+
+// class and member flags, from zend_compile.h
+const ZEND_ACC_FINAL: u32 = 0x04;
+const ZEND_ACC_INTERFACE: u32 = 0x40;
+const ZEND_ACC_TRAIT: u32 = 0x80;
+const ZEND_ACC_PROTECTED: u32 = 0x200;
+const ZEND_ACC_PRIVATE: u32 = 0x400;
+
+// the attr of a name node
+pub const ZEND_NAME_FQ: u32 = 0;
+pub const ZEND_NAME_NOT_FQ: u32 = 1;
+pub const ZEND_NAME_RELATIVE: u32 = 2;
+
+// the attr of a ZEND_AST_PARAM
+const ZEND_PARAM_REF: u32 = 1 << 0;
+const ZEND_PARAM_VARIADIC: u32 = 1 << 1;
+
+/**
+ * A kind of symbol which names are resolved for, and which `use` statements
+ * import.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Class,
+    Function,
+    Constant,
+}
+
+impl SymbolKind {
+    fn from_attr(attr: php::zend_ast_attr) -> Option<SymbolKind> {
+        match unsafe { use_type(attr) } {
+            1 => Some(SymbolKind::Class),
+            2 => Some(SymbolKind::Function),
+            3 => Some(SymbolKind::Constant),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * The names imported by `use` statements in the current namespace, mapping
+ * each alias to the fully qualified name (without a leading backslash) it
+ * stands for.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Imports {
+    /// Class aliases, lowercased since class names are case-insensitive.
+    pub classes: HashMap<Vec<u8>, Vec<u8>>,
+    /// Function aliases, lowercased.
+    pub functions: HashMap<Vec<u8>, Vec<u8>>,
+    /// Constant aliases, which are case-sensitive.
+    pub constants: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Imports {
+    /**
+     * Returns the name imported as `alias`, if any.
+     */
+    pub fn get(&self, kind: SymbolKind, alias: &[u8]) -> Option<&[u8]> {
+        let found = match kind {
+            SymbolKind::Class => self.classes.get(&alias.to_ascii_lowercase()),
+            SymbolKind::Function => self.functions.get(&alias.to_ascii_lowercase()),
+            SymbolKind::Constant => self.constants.get(alias),
+        };
+        found.map(|name| name.as_slice())
+    }
+
+    fn add(&mut self, kind: SymbolKind, alias: &[u8], name: Vec<u8>) {
+        match kind {
+            SymbolKind::Class => self.classes.insert(alias.to_ascii_lowercase(), name),
+            SymbolKind::Function => self.functions.insert(alias.to_ascii_lowercase(), name),
+            SymbolKind::Constant => self.constants.insert(alias.to_vec(), name),
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClassKind {
+    Class,
+    Trait,
+    Interface,
+}
+
+/**
+ * The class, trait or interface a node is declared in.
+ */
+#[derive(Clone, Debug)]
+pub struct ClassScope {
+    /// The fully qualified name, or None for an anonymous class.
+    pub name: Option<Vec<u8>>,
+    pub kind: ClassKind,
+    /// The declaration's ZEND_ACC_* flags.
+    pub flags: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FunctionKind {
+    Function,
+    Method,
+    Closure,
+}
+
+/**
+ * A parameter of a function, or a variable bound by a closure's `use`.
+ */
+#[derive(Clone, Debug)]
+pub struct Param {
+    /// The variable's name, without the `$`.
+    pub name: Vec<u8>,
+    pub by_ref: bool,
+    pub variadic: bool,
+}
+
+/**
+ * The function, method or closure a node is declared in.
+ */
+#[derive(Clone, Debug)]
+pub struct FunctionScope {
+    /// The fully qualified name of a function, the name of a method, or None
+    /// for a closure.
+    pub name: Option<Vec<u8>>,
+    pub kind: FunctionKind,
+    pub params: Vec<Param>,
+    /// The variables bound by a closure's `use`.
+    pub uses: Vec<Param>,
+}
+
+/**
+ * A function declared in the file, along with the names it was declared
+ * with, which the names in its body are resolved against.
+ */
+#[derive(Clone, Debug)]
+pub struct DeclaredFunction {
+    /// The ZEND_AST_FUNC_DECL.
+    pub decl: ZendAst,
+    pub namespace: Vec<u8>,
+    pub imports: Imports,
+}
+
+/**
+ * Where a node is, within the declarations of the file. The counters are
+ * reset at each function or class boundary, so a statement in a function
+ * which is declared in a loop isn't in a loop.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+    pub class: Option<ClassScope>,
+    pub function: Option<FunctionScope>,
+    /// How many loops the node is in, including their conditions.
+    pub loops: u32,
+    /// How many `try` blocks the node is in; catch and finally blocks don't
+    /// count.
+    pub tries: u32,
+    /// How many branches the node is in: the body of an if, a switch case or
+    /// a catch block, or an operand of `?:`, `&&`, `||` or `??` which isn't
+    /// always evaluated.
+    pub conditionals: u32,
+}
+
+/**
+ * The context of the node a rule is optimizing, as tracked by walk.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct PhpContext {
+    /// The current namespace, without a leading backslash; empty in the
+    /// global namespace.
+    pub namespace: Vec<u8>,
+    pub imports: Imports,
+    pub scope: Scope,
+    /// Constants declared with literal values by the statements walked so
+    /// far, with `const` or `define()` outside of any function, class, loop
+    /// or branch; keyed by constant_key.
+    pub constants: HashMap<Vec<u8>, Value>,
+    /// The public constants with literal values of final classes declared
+    /// so far, by lowercased fully qualified class name and then constant
+    /// name.
+    pub class_constants: HashMap<Vec<u8>, HashMap<Vec<u8>, Value>>,
+    /// The functions declared by the statements walked so far outside of
+    /// any function, class, loop or branch, by lowercased fully qualified
+    /// name.
+    pub functions: HashMap<Vec<u8>, DeclaredFunction>,
+}
+
 impl PhpContext {
     pub fn new() -> Self {
-        PhpContext
+        PhpContext::default()
+    }
+
+    pub fn in_loop(&self) -> bool {
+        self.scope.loops > 0
+    }
+
+    pub fn in_try(&self) -> bool {
+        self.scope.tries > 0
+    }
+
+    pub fn in_conditional(&self) -> bool {
+        self.scope.conditionals > 0
+    }
+
+    /**
+     * The value of a constant declared earlier in the file, by fully
+     * qualified name.
+     */
+    pub fn constant(&self, name: &[u8]) -> Option<&Value> {
+        self.constants.get(&constant_key(name))
+    }
+
+    /**
+     * The value of a class constant declared earlier in the file, by the
+     * class's fully qualified name.
+     */
+    pub fn class_constant(&self, class: &[u8], name: &[u8]) -> Option<&Value> {
+        self.class_constants.get(&class.to_ascii_lowercase()).and_then(|constants| constants.get(name))
+    }
+
+    /**
+     * A function declared earlier in the file, by fully qualified name.
+     */
+    pub fn function(&self, name: &[u8]) -> Option<&DeclaredFunction> {
+        self.functions.get(&name.to_ascii_lowercase())
+    }
+
+    /**
+     * Qualify a name declared in the current namespace.
+     */
+    pub fn qualify(&self, name: &[u8]) -> Vec<u8> {
+        if self.namespace.is_empty() {
+            name.to_vec()
+        } else {
+            [&self.namespace[..], &b"\\"[..], name].concat()
+        }
+    }
+
+    /**
+     * Resolve a name of the given kind, as written in the current namespace
+     * with the attr `attr`, to a fully qualified name (without a leading
+     * backslash). The second name is the global one which an unqualified
+     * function or constant name falls back to if the namespaced one isn't
+     * defined.
+     *
+     * The class names self, parent and static are returned as they are.
+     */
+    pub fn resolve(&self, kind: SymbolKind, name: &[u8], attr: u32) -> (Vec<u8>, Option<Vec<u8>>) {
+        match attr {
+            ZEND_NAME_FQ => return (trim_separator(name).to_vec(), None),
+            ZEND_NAME_RELATIVE => return (self.qualify(name), None),
+            _ => {}
+        }
+        match name.iter().position(|&c| c == b'\\') {
+            // the first part of a qualified name can be an imported namespace
+            Some(separator) => {
+                let (first, rest) = name.split_at(separator);
+                match self.imports.get(SymbolKind::Class, first) {
+                    Some(imported) => ([imported, rest].concat(), None),
+                    None => (self.qualify(name), None),
+                }
+            }
+            None => {
+                if kind == SymbolKind::Class && is_special_class(name) {
+                    return (name.to_vec(), None);
+                }
+                if let Some(imported) = self.imports.get(kind, name) {
+                    return (imported.to_vec(), None);
+                }
+                if kind == SymbolKind::Class || self.namespace.is_empty() {
+                    (self.qualify(name), None)
+                } else {
+                    (self.qualify(name), Some(name.to_vec()))
+                }
+            }
+        }
+    }
+
+    /**
+     * Update the context for a child of `parent`: the node at `index` among
+     * its children, which is `child`. Returns the namespace and imports to
+     * restore once the child has been walked, for a braced namespace.
+     */
+    fn enter(&mut self, parent: ZendAst, index: usize, child: ZendAst) -> Option<(Vec<u8>, Imports)> {
+        // a statement which is always run if the file is
+        let unconditional = ast::kind_of(parent) == ZEND_AST_STMT_LIST && self.scope.class.is_none() &&
+            self.scope.function.is_none() && self.scope.loops == 0 && self.scope.tries == 0 &&
+            self.scope.conditionals == 0;
+        match (ast::kind_of(parent), index) {
+            (ZEND_AST_FOR, 1..=3) | (ZEND_AST_WHILE, _) | (ZEND_AST_DO_WHILE, _) |
+            (ZEND_AST_FOREACH, 1..=3) => self.scope.loops += 1,
+            (ZEND_AST_TRY, 0) => self.scope.tries += 1,
+            // the first condition of an if is always evaluated
+            (ZEND_AST_IF, 0) => {}
+            // catch blocks only run if the try block throws
+            (ZEND_AST_IF, _) | (ZEND_AST_IF_ELEM, 1) | (ZEND_AST_SWITCH, 1) | (ZEND_AST_TRY, 1) |
+            (ZEND_AST_CONDITIONAL, 1..=2) | (ZEND_AST_AND, 1) | (ZEND_AST_OR, 1) |
+            (ZEND_AST_COALESCE, 1) => self.scope.conditionals += 1,
+            _ => {}
+        }
+
+        match ast::kind_of(child) {
+            ZEND_AST_NAMESPACE => {
+                let children = ast::get_raw_children(child);
+                let name = string_of(children[0]).unwrap_or_default();
+                let outer = ::std::mem::replace(&mut self.namespace, trim_separator(&name).to_vec());
+                let outer_imports = ::std::mem::replace(&mut self.imports, Imports::default());
+                if !children[1].is_null() {
+                    return Some((outer, outer_imports));
+                }
+            }
+            ZEND_AST_USE => self.add_imports(child, b"", None),
+            ZEND_AST_GROUP_USE => {
+                let children = ast::get_raw_children(child);
+                let prefix = string_of(children[0]).unwrap_or_default();
+                let kind = SymbolKind::from_attr(unsafe { (*child).attr });
+                self.add_imports(children[1], trim_separator(&prefix), kind);
+            }
+            ZEND_AST_CONST_DECL if unconditional => {
+                for elem in ast::get_raw_children(child) {
+                    let children = ast::get_raw_children(elem);
+                    if let Some(name) = string_of(children[0]) {
+                        let name = self.qualify(&name);
+                        self.declare_constant(&name, children[1]);
+                    }
+                }
+            }
+            ZEND_AST_CALL if unconditional => self.enter_define(child),
+            kind if unsafe { ast_is_decl(child) } => {
+                if kind == ZEND_AST_CLASS && unconditional {
+                    self.declare_class_constants(child);
+                }
+                if kind == ZEND_AST_FUNC_DECL && unconditional {
+                    self.declare_function(child);
+                }
+                self.enter_decl(kind, child);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /**
+     * Record a constant's value if `value` is a literal. A constant can't be
+     * redefined, so the first declaration wins.
+     */
+    fn declare_constant(&mut self, name: &[u8], value: ZendAst) {
+        if let Some(value) = Value::from_node(value) {
+            self.constants.entry(constant_key(name)).or_insert(value);
+        }
+    }
+
+    /**
+     * Record the constant defined by a call to define(), if it is one.
+     * Constants defined with the case-insensitive flag aren't recorded.
+     */
+    fn enter_define(&mut self, call: ZendAst) {
+        let children = ast::get_raw_children(call);
+        let function = match string_of(children[0]) {
+            Some(function) => function,
+            None => return,
+        };
+        let (function, fallback) = self.resolve(SymbolKind::Function, &function, unsafe { (*children[0]).attr } as u32);
+        if !ast::names_equal(&function, b"define") && !fallback.map_or(false, |f| ast::names_equal(&f, b"define")) {
+            return;
+        }
+        let args = ast::get_raw_children(children[1]);
+        if args.len() != 2 || ast::kind_of(args[0]) != ZEND_AST_ZVAL {
+            return;
+        }
+        if let Some(name) = string_of(args[0]) {
+            // define() doesn't strip a leading backslash
+            if !name.starts_with(b"\\") {
+                self.declare_constant(&name, args[1]);
+            }
+        }
+    }
+
+    fn declare_function(&mut self, decl: ZendAst) {
+        let name = unsafe { ast::zend_str_bytes((*(decl as ast::ZendAstDecl)).name) };
+        let function = DeclaredFunction {
+            decl: decl,
+            namespace: self.namespace.clone(),
+            imports: self.imports.clone(),
+        };
+        self.functions.entry(self.qualify(name).to_ascii_lowercase()).or_insert(function);
+    }
+
+    fn declare_class_constants(&mut self, class: ZendAst) {
+        let decl = class as ast::ZendAstDecl;
+        let (flags, name) = unsafe { ((*decl).flags, (*decl).name) };
+        if flags & ZEND_ACC_FINAL == 0 || name.is_null() {
+            return;
+        }
+        let name = self.qualify(unsafe { ast::zend_str_bytes(name) }).to_ascii_lowercase();
+        if self.class_constants.contains_key(&name) {
+            return;
+        }
+        let mut constants = HashMap::new();
+        let stmts = ast::get_raw_children(class)[2];
+        if !stmts.is_null() {
+            for stmt in ast::get_raw_children(stmts) {
+                let attr = unsafe { (*stmt).attr } as u32;
+                if ast::kind_of(stmt) != ZEND_AST_CLASS_CONST_DECL || attr & (ZEND_ACC_PROTECTED | ZEND_ACC_PRIVATE) != 0 {
+                    continue;
+                }
+                for elem in ast::get_raw_children(stmt) {
+                    let children = ast::get_raw_children(elem);
+                    if let (Some(name), Some(value)) = (string_of(children[0]), Value::from_node(children[1])) {
+                        constants.insert(name, value);
+                    }
+                }
+            }
+        }
+        self.class_constants.insert(name, constants);
+    }
+
+    fn add_imports(&mut self, list: ZendAst, prefix: &[u8], group_kind: Option<SymbolKind>) {
+        let list_kind = SymbolKind::from_attr(unsafe { (*list).attr }).or(group_kind);
+        for elem in ast::get_raw_children(list) {
+            let kind = SymbolKind::from_attr(unsafe { (*elem).attr }).or(list_kind)
+                .unwrap_or(SymbolKind::Class);
+            let children = ast::get_raw_children(elem);
+            let name = match string_of(children[0]) {
+                Some(name) => name,
+                None => continue,
+            };
+            let name = trim_separator(&name);
+            let full_name = if prefix.is_empty() {
+                name.to_vec()
+            } else {
+                [prefix, &b"\\"[..], name].concat()
+            };
+            let alias = string_of(children[1]).unwrap_or_else(|| {
+                let start = name.iter().rposition(|&c| c == b'\\').map_or(0, |i| i + 1);
+                name[start..].to_vec()
+            });
+            self.imports.add(kind, &alias, full_name);
+        }
+    }
+
+    fn enter_decl(&mut self, kind: php::_zend_ast_kind, zast: ZendAst) {
+        let decl = zast as ast::ZendAstDecl;
+        let (flags, name) = unsafe {
+            let name = (*decl).name;
+            ((*decl).flags, if name.is_null() { None } else { Some(ast::zend_str_bytes(name).to_vec()) })
+        };
+        let class = self.scope.class.take();
+        self.scope = Scope::default();
+        match kind {
+            ZEND_AST_CLASS => {
+                let kind = if flags & ZEND_ACC_INTERFACE != 0 {
+                    ClassKind::Interface
+                } else if flags & ZEND_ACC_TRAIT != 0 {
+                    ClassKind::Trait
+                } else {
+                    ClassKind::Class
+                };
+                // anonymous classes are given a name when they're compiled
+                let name = name.map(|name| self.qualify(&name));
+                self.scope.class = Some(ClassScope { name: name, kind: kind, flags: flags });
+            }
+            ZEND_AST_FUNC_DECL | ZEND_AST_METHOD | ZEND_AST_CLOSURE => {
+                let children = ast::get_raw_children(zast);
+                let (kind, name) = match kind {
+                    ZEND_AST_FUNC_DECL => (FunctionKind::Function, name.map(|name| self.qualify(&name))),
+                    ZEND_AST_METHOD => (FunctionKind::Method, name),
+                    _ => (FunctionKind::Closure, None),
+                };
+                // a method's class is its own; a closure's is the one it's
+                // declared in
+                if kind != FunctionKind::Function {
+                    self.scope.class = class;
+                }
+                self.scope.function = Some(FunctionScope {
+                    name: name,
+                    kind: kind,
+                    params: params(children[0]),
+                    uses: uses(children[1]),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/**
+ * Recursively walk an AST like ast::node_walk, calling `f` on every child
+ * with `ctx` describing where the child is. Each child is passed to `f`
+ * before the context is updated for it, so a rule matching a loop sees the
+ * context outside the loop.
+ */
+pub fn walk<F>(zast: ZendAst, ctx: &mut PhpContext, f: &mut F) where F: FnMut(&mut ZendAstPtr, &mut PhpContext) {
+    for (index, mut child) in ast::get_indexed_children(zast) {
+        f(&mut child, ctx);
+        let child = ZendAst::from(child);
+        // unbraced namespaces and use statements apply to the statements
+        // after them, so they aren't undone
+        let scope = ctx.scope.clone();
+        let names = ctx.enter(zast, index, child);
+        walk(child, ctx, f);
+        ctx.scope = scope;
+        if let Some((namespace, imports)) = names {
+            ctx.namespace = namespace;
+            ctx.imports = imports;
+        }
+    }
+}
+
+/**
+ * Returns true if `name` is self, parent or static, which refer to classes
+ * relative to the one they're used in.
+ */
+pub fn is_special_class(name: &[u8]) -> bool {
+    ["self", "parent", "static"].iter().any(|special| ast::names_equal(special.as_bytes(), name))
+}
+
+/**
+ * Compare two fully qualified names of the given kind. Namespaces, classes
+ * and functions are case-insensitive; constants are case-sensitive apart from
+ * their namespace, except for true, false and null.
+ */
+pub fn symbols_equal(kind: SymbolKind, a: &[u8], b: &[u8]) -> bool {
+    if kind != SymbolKind::Constant {
+        return ast::names_equal(a, b);
+    }
+    let split = |name: &[u8]| -> (usize, bool) {
+        let start = name.iter().rposition(|&c| c == b'\\').map_or(0, |i| i + 1);
+        let literal = ["true", "false", "null"].iter().any(|l| ast::names_equal(l.as_bytes(), &name[start..]));
+        (start, literal)
+    };
+    let ((start_a, literal_a), (start_b, literal_b)) = (split(a), split(b));
+    ast::names_equal(&a[..start_a], &b[..start_b]) && if literal_a && literal_b {
+        ast::names_equal(&a[start_a..], &b[start_b..])
+    } else {
+        a[start_a..] == b[start_b..]
+    }
+}
+
+/**
+ * The key a constant is stored under in PHP's constant table: its namespace
+ * is case-insensitive, so it's lowercased, while the rest of the name is
+ * case-sensitive.
+ */
+pub fn constant_key(name: &[u8]) -> Vec<u8> {
+    let start = name.iter().rposition(|&c| c == b'\\').map_or(0, |i| i + 1);
+    let mut key = name[..start].to_ascii_lowercase();
+    key.extend_from_slice(&name[start..]);
+    key
+}
+
+fn string_of(zast: ZendAst) -> Option<Vec<u8>> {
+    if zast.is_null() || ast::kind_of(zast) != ZEND_AST_ZVAL {
+        return None;
+    }
+    match Value::from_zval_node(zast) {
+        Some(Value::String(name)) => Some(name),
+        _ => None,
+    }
+}
+
+fn trim_separator(name: &[u8]) -> &[u8] {
+    if name.first() == Some(&b'\\') { &name[1..] } else { name }
+}
+
+fn params(list: ZendAst) -> Vec<Param> {
+    if list.is_null() {
+        return Vec::new();
+    }
+    ast::get_raw_children(list).into_iter().filter_map(|param| {
+        let attr = unsafe { (*param).attr } as u32;
+        string_of(ast::get_raw_children(param)[1]).map(|name| Param {
+            name: name,
+            by_ref: attr & ZEND_PARAM_REF != 0,
+            variadic: attr & ZEND_PARAM_VARIADIC != 0,
+        })
+    }).collect()
+}
+
+fn uses(list: ZendAst) -> Vec<Param> {
+    if list.is_null() {
+        return Vec::new();
     }
+    // a by-reference use has an attr of 1
+    ast::get_raw_children(list).into_iter().filter_map(|var| {
+        let by_ref = unsafe { (*var).attr } != 0;
+        string_of(var).map(|name| Param { name: name, by_ref: by_ref, variadic: false })
+    }).collect()
 }
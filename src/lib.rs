@@ -2,38 +2,349 @@
 
 #[macro_use]
 pub mod ast;
+pub mod callback;
+pub mod constraint;
 pub mod context;
+pub mod pattern;
 pub mod php;
+pub mod purity;
 pub mod rules;
 
 #[macro_use]
 extern crate lazy_static;
 
+use std::cell::RefCell;
 use std::os::raw::c_char;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
 use ast::ZendAst;
+use callback::PhpCallable;
 
+extern "C" {
+    fn rules_array_add(
+        arr: *mut php::zval,
+        name: *const c_char,
+        name_len: usize,
+        description: *const c_char,
+        description_len: usize,
+        priority: php::zend_long,
+        enabled: php::zend_bool,
+        persistent: php::zend_bool,
+        level: php::zend_long,
+        safety: *const c_char
+    );
+}
+
+thread_local! {
+    /// The message and location of the last panic, recorded by the panic
+    /// hook for catch_panic to report.
+    static LAST_PANIC: RefCell<Option<String>> = RefCell::new(None);
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+/**
+ * Run the body of an extern "C" function, catching any panic so it can't
+ * unwind into the Zend engine. A panic is reported as a PHP warning, ending
+ * with `consequence`, and `default` is returned instead.
+ */
+fn catch_panic<F, R>(function: &str, consequence: &str, default: R, f: F) -> R where F: FnOnce() -> R {
+    PANIC_HOOK.call_once(|| {
+        // record panics instead of printing them to stderr
+        panic::set_hook(Box::new(|info| {
+            let message = match info.payload().downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => match info.payload().downcast_ref::<String>() {
+                    Some(s) => s.clone(),
+                    None => "unknown error".to_string(),
+                },
+            };
+            let message = match info.location() {
+                Some(location) => format!("{} at {}:{}", message, location.file(), location.line()),
+                None => message,
+            };
+            LAST_PANIC.with(|last| *last.borrow_mut() = Some(message));
+        }));
+    });
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => {
+            let message = LAST_PANIC.with(|last| last.borrow_mut().take())
+                .unwrap_or_else(|| "unknown error".to_string());
+            callback::warn(&format!("pphp: internal error in {}: {}; {}", function, message, consequence));
+            default
+        }
+    }
+}
+
+/**
+ * Read a string argument of `len` bytes, which may include NULs, raising a
+ * warning and returning None if it isn't valid UTF-8.
+ */
+fn utf8_arg(s: *const c_char, len: usize, what: &str) -> Option<String> {
+    match ::std::str::from_utf8(unsafe { ::std::slice::from_raw_parts(s as *const u8, len) }) {
+        Ok(s) => Some(s.to_string()),
+        Err(_) => {
+            callback::warn(&format!("pphp: {} must be valid UTF-8", what));
+            None
+        }
+    }
+}
+
+/**
+ * Returns true if a callback bailed out, which the caller must re-raise with
+ * zend_bailout() now that nothing is held here.
+ */
 #[no_mangle]
-pub extern "C" fn rust_pphp_optimize_ast(zast: ZendAst) {
-    rules::apply_all(zast);
+pub extern "C" fn rust_pphp_optimize_ast(zast: ZendAst) -> php::zend_bool {
+    catch_panic("rust_pphp_optimize_ast", "the code was compiled without being optimized", (), || {
+        if ast::options().enabled {
+            ast::undo_on_panic(|| rules::apply_all(zast));
+        }
+    });
+    callback::take_bailout() as php::zend_bool
 }
 
 #[no_mangle]
-pub extern "C" fn rust_pphp_add_rule(replace: *const c_char, with: *const c_char) -> php::zend_bool {
-    let replace = unsafe {CStr::from_ptr(replace)}.to_str().unwrap().to_string();
-    let with = unsafe {CStr::from_ptr(with)}.to_str().unwrap().to_string();
-    match rules::custom::CustomSubstitution::try_create(replace, with) {
-        Some(rule) => {
-            rules::add_rule(Box::new(rule));
-            1
+pub extern "C" fn rust_pphp_add_rule(
+    replace: *const c_char,
+    replace_len: usize,
+    with: *const c_char,
+    with_len: usize,
+    guard: *mut php::zval,
+    name: *const c_char
+) -> php::zend_bool {
+    catch_panic("rust_pphp_add_rule", "the rule wasn't added", 0, || {
+        let replace = utf8_arg(replace, replace_len, "the search pattern");
+        let with = utf8_arg(with, with_len, "the replacement");
+        let (replace, with) = match (replace, with) {
+            (Some(replace), Some(with)) => (replace, with),
+            _ => return 0,
+        };
+        let guard = if guard.is_null() {
+            None
+        } else {
+            Some(PhpCallable::new(guard))
+        };
+        match rules::custom::CustomSubstitution::try_create(replace, with, guard) {
+            Ok(rule) => add_named_rule(name, Box::new(rule)),
+            Err(e) => {
+                callback::warn(&format!("pphp: {}", e));
+                0
+            }
         }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn rust_pphp_add_callback_rule(
+    pattern: *const c_char,
+    pattern_len: usize,
+    callback: *mut php::zval,
+    name: *const c_char
+) -> php::zend_bool {
+    catch_panic("rust_pphp_add_callback_rule", "the rule wasn't added", 0, || {
+        let pattern = match utf8_arg(pattern, pattern_len, "the pattern") {
+            Some(pattern) => pattern,
+            None => return 0,
+        };
+        match rules::custom::CustomCallback::try_create(pattern, PhpCallable::new(callback)) {
+            Ok(rule) => add_named_rule(name, Box::new(rule)),
+            Err(e) => {
+                callback::warn(&format!("pphp: invalid pattern: {}", e));
+                0
+            }
+        }
+    })
+}
+
+/**
+ * Register a custom rule under `name`, or a generated name if it's NULL.
+ */
+fn add_named_rule(name: *const c_char, rule: Box<rules::AstOptimizationRule>) -> php::zend_bool {
+    let name = if name.is_null() {
+        None
+    } else {
+        match utf8_arg(name, unsafe {CStr::from_ptr(name)}.to_bytes().len(), "rule names") {
+            Some(name) => Some(name),
+            None => return 0,
+        }
+    };
+    match rules::add_rule(rules::RuleOptions::new(name.clone()), rule) {
+        Some(_) => 1,
         None => {
+            // while rules are being applied, add_rule has already explained
+            if !rules::applying() {
+                callback::warn(&format!("pphp: a rule named '{}' already exists", name.unwrap_or_default()));
+            }
             0
         }
     }
 }
 
+#[no_mangle]
+pub extern "C" fn rust_pphp_remove_rule(name: *const c_char) -> php::zend_bool {
+    catch_panic("rust_pphp_remove_rule", "no rule was removed", 0, || {
+        let name = unsafe {CStr::from_ptr(name)}.to_string_lossy();
+        rules::remove_rule(&name) as php::zend_bool
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn rust_pphp_set_rule_enabled(name: *const c_char, enabled: php::zend_bool) -> php::zend_bool {
+    catch_panic("rust_pphp_set_rule_enabled", "the rule wasn't changed", 0, || {
+        let name = unsafe {CStr::from_ptr(name)}.to_string_lossy();
+        rules::set_rule_enabled(&name, enabled != 0) as php::zend_bool
+    })
+}
+
+/**
+ * Fill `arr`, an initialized PHP array, with an entry for each registered
+ * rule.
+ */
+#[no_mangle]
+pub extern "C" fn rust_pphp_list_rules(arr: *mut php::zval) {
+    catch_panic("rust_pphp_list_rules", "the list is incomplete", (), || {
+        for rule in rules::list_rules() {
+            let safety = rule.safety.map(|safety| CString::new(safety.name()).unwrap());
+            unsafe {
+                rules_array_add(
+                    arr,
+                    rule.name.as_ptr() as *const c_char, rule.name.len(),
+                    rule.description.as_ptr() as *const c_char, rule.description.len(),
+                    rule.priority as php::zend_long,
+                    rule.enabled as php::zend_bool,
+                    rule.persistent as php::zend_bool,
+                    rule.level.map_or(-1, |level| level as php::zend_long),
+                    safety.as_ref().map_or(::std::ptr::null(), |safety| safety.as_ptr())
+                );
+            }
+        }
+    })
+}
+
+/**
+ * Load a rule file (see rules::file), adding its rules as persistent rules or
+ * for the current request. Returns the number of rules added, or -1 if the
+ * file couldn't be loaded. Called at module startup with the pphp.rules_file
+ * INI setting, and by pphp_load_rules.
+ */
+#[no_mangle]
+pub extern "C" fn rust_pphp_load_rules_file(path: *const c_char, persistent: php::zend_bool) -> php::zend_long {
+    catch_panic("rust_pphp_load_rules_file", "no rules were loaded", -1, || {
+        let path = unsafe {CStr::from_ptr(path)}.to_string_lossy();
+        match rules::file::load(&path, persistent != 0) {
+            Ok(count) => count as php::zend_long,
+            Err(e) => {
+                callback::warn(&format!("pphp: couldn't load rules from {}: {}", path, e));
+                -1
+            }
+        }
+    })
+}
+
+/**
+ * Drop the rules added during the request that's ending.
+ */
+#[no_mangle]
+pub extern "C" fn rust_pphp_request_shutdown() {
+    catch_panic("rust_pphp_request_shutdown", "the request's rules may not have been removed", (), || {
+        rules::end_request();
+    })
+}
+
+/**
+ * Returns the name of the `index`th zend_ast_kind, storing its value in
+ * `kind`, or NULL once `index` is past the last kind. Used to register the
+ * PPHP_AST_* constants.
+ */
+#[no_mangle]
+pub extern "C" fn rust_pphp_ast_kind_name(index: u32, kind: *mut u16) -> *const c_char {
+    catch_panic("rust_pphp_ast_kind_name", "not all PPHP_AST_* constants are defined", ::std::ptr::null(), || {
+        match AST_KIND_NAMES.get(index as usize) {
+            Some(&(k, ref name)) => {
+                unsafe { *kind = k };
+                name.as_ptr()
+            }
+            None => ::std::ptr::null(),
+        }
+    })
+}
+
+lazy_static! {
+    static ref AST_KIND_NAMES: Vec<(u16, CString)> = {
+        constraint::AST_KINDS.iter().map(|&kind| {
+            (kind as u16, CString::new(format!("{:?}", kind)).unwrap())
+        }).collect()
+    };
+}
+
+/**
+ * The following are called when the corresponding pphp.* INI setting
+ * changes, including at startup.
+ */
+#[no_mangle]
+pub extern "C" fn rust_pphp_set_enabled(enabled: php::zend_bool) {
+    catch_panic("rust_pphp_set_enabled", "the setting wasn't changed", (), || {
+        ast::update_options(|options| options.enabled = enabled != 0);
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn rust_pphp_set_debug_trace(enabled: php::zend_bool) {
-    ast::set_debug_trace(enabled != 0);
+    catch_panic("rust_pphp_set_debug_trace", "the setting wasn't changed", (), || {
+        ast::set_debug_trace(enabled != 0);
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn rust_pphp_set_trace_output(output: *const c_char) {
+    catch_panic("rust_pphp_set_trace_output", "the setting wasn't changed", (), || {
+        let output = unsafe {CStr::from_ptr(output)}.to_string_lossy().trim().to_string();
+        ast::update_options(|options| options.trace_output = output);
+    })
+}
+
+/**
+ * `names` is a comma-separated list of rule names.
+ */
+#[no_mangle]
+pub extern "C" fn rust_pphp_set_disabled_rules(names: *const c_char) {
+    catch_panic("rust_pphp_set_disabled_rules", "the setting wasn't changed", (), || {
+        let names = unsafe {CStr::from_ptr(names)}.to_string_lossy();
+        let names = names.split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+        ast::update_options(|options| options.disabled_rules = names);
+    })
+}
+
+/**
+ * Returns false, leaving the setting unchanged, if `level` isn't valid.
+ */
+#[no_mangle]
+pub extern "C" fn rust_pphp_set_optimization_level(level: php::zend_long) -> php::zend_bool {
+    catch_panic("rust_pphp_set_optimization_level", "the setting wasn't changed", 0, || {
+        if level < rules::LEVEL_NONE as php::zend_long || level > rules::LEVEL_AGGRESSIVE as php::zend_long {
+            return 0;
+        }
+        ast::update_options(|options| options.optimization_level = level as u8);
+        1
+    })
+}
+
+/**
+ * Returns false, leaving the setting unchanged, if `limit` is negative.
+ */
+#[no_mangle]
+pub extern "C" fn rust_pphp_set_unroll_limit(limit: php::zend_long) -> php::zend_bool {
+    catch_panic("rust_pphp_set_unroll_limit", "the setting wasn't changed", 0, || {
+        if limit < 0 || limit > u32::max_value() as php::zend_long {
+            return 0;
+        }
+        ast::update_options(|options| options.unroll_limit = limit as u32);
+        1
+    })
 }
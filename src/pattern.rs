@@ -0,0 +1,1147 @@
+use ::std::collections::HashMap;
+use ::std::os::raw::c_char;
+use ast::{self, ZendAst, ZendAstDecl};
+use constraint::{self, Constraint};
+use context::{self, PhpContext, SymbolKind};
+use php;
+
+extern "C" {
+    fn ast_is_list(zast: ZendAst) -> bool;
+    fn ast_is_decl(zast: ZendAst) -> bool;
+    fn ast_zval(zast: ZendAst) -> *mut php::zval;
+    fn ast_lineno(zast: ZendAst) -> u32;
+    fn zval_type(zval: *mut php::zval) -> u8;
+    fn zval_long(zval: *mut php::zval) -> php::zend_long;
+    fn zval_double(zval: *mut php::zval) -> f64;
+    fn zval_str(zval: *mut php::zval) -> *mut php::zend_string;
+    fn zval_string(zval: *mut php::zval) -> *mut php::zend_string;
+    fn string_init(val: *const c_char, len: usize) -> *mut php::zend_string;
+    fn ast_create_name(name: *mut php::zend_string) -> ZendAst;
+    fn ast_create_zval_value(
+        zval_type: u8,
+        lval: php::zend_long,
+        dval: f64,
+        sval: *const c_char,
+        slen: usize,
+        attr: php::zend_ast_attr,
+        lineno: u32
+    ) -> ZendAst;
+}
+
+/**
+ * The node(s) bound to a PPHP::$variable by a successful match.
+ */
+#[derive(Clone, Debug)]
+pub enum Binding {
+    /// A single node, bound by an ordinary variable.
+    Node(ZendAst),
+    /// Zero or more consecutive list elements, bound by a variadic variable.
+    List(Vec<ZendAst>),
+}
+
+pub type Bindings = HashMap<String, Binding>;
+
+/**
+ * A PPHP::$variable found in a pattern, along with any constraints on the
+ * nodes it's allowed to bind to.
+ */
+#[derive(Clone, Debug)]
+pub struct BindParam {
+    pub name: String,
+    pub constraints: Vec<Constraint>,
+    pub variadic: bool,
+}
+
+impl BindParam {
+    /**
+     * Parse the variable name of a PPHP::$variable. A name in the form
+     * `name:constraint` (written as `PPHP::${'name:constraint'}`) restricts
+     * the binding; see constraint::Constraint. A name starting with `...`
+     * is variadic, binding zero or more elements of the enclosing list.
+     *
+     * Returns None if the constraints aren't valid.
+     */
+    pub fn parse(var_name: &str) -> Option<BindParam> {
+        let variadic = var_name.starts_with("...");
+        let var_name = if variadic { &var_name[3..] } else { var_name };
+        match var_name.find(':') {
+            Some(i) => {
+                constraint::parse_constraints(&var_name[i + 1..]).map(|constraints| {
+                    BindParam {
+                        name: var_name[..i].to_string(),
+                        constraints: constraints,
+                        variadic: variadic,
+                    }
+                })
+            }
+            None => Some(BindParam {
+                name: var_name.to_string(),
+                constraints: Vec::new(),
+                variadic: variadic,
+            }),
+        }
+    }
+
+    /**
+     * Returns true if this variable is allowed to bind to `zast`.
+     */
+    pub fn accepts(&self, zast: ZendAst) -> bool {
+        self.constraints.is_empty() || self.constraints.iter().any(|c| c.accepts(zast))
+    }
+}
+
+impl ::std::fmt::Display for BindParam {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let prefix = if self.variadic { "..." } else { "" };
+        if self.constraints.is_empty() {
+            write!(f, "{}${}", prefix, self.name)
+        } else {
+            let constraints = self.constraints.iter().map(|c| c.to_string()).collect::<Vec<String>>();
+            write!(f, "{}${}:{}", prefix, self.name, constraints.join("|"))
+        }
+    }
+}
+
+/**
+ * Identifier variables are names starting with this prefix, e.g. the
+ * function name in `PPHP__fn()`.
+ */
+pub const IDENTIFIER_PREFIX: &'static str = "PPHP__";
+
+/**
+ * Returns the name of an identifier variable, if `name` is one.
+ */
+fn identifier_param_name(name: &str) -> Option<&str> {
+    if name.starts_with(IDENTIFIER_PREFIX) && name.len() > IDENTIFIER_PREFIX.len() {
+        Some(&name[IDENTIFIER_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+/**
+ * A literal value from a ZVAL node.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    False,
+    True,
+    Long(php::zend_long),
+    Double(f64),
+    String(Vec<u8>),
+}
+
+impl Value {
+    /**
+     * Read the value of a ZVAL node. Returns None for values which can't
+     * appear in parsed PHP, such as arrays.
+     */
+    pub fn from_zval_node(zast: ZendAst) -> Option<Value> {
+        unsafe {
+            let zv = ast_zval(zast);
+            match zval_type(zv) as u32 {
+                php::IS_NULL => Some(Value::Null),
+                php::IS_FALSE => Some(Value::False),
+                php::IS_TRUE => Some(Value::True),
+                php::IS_LONG => Some(Value::Long(zval_long(zv))),
+                php::IS_DOUBLE => Some(Value::Double(zval_double(zv))),
+                php::IS_STRING => Some(Value::String(ast::zend_str_bytes(zval_str(zv)).to_vec())),
+                _ => None,
+            }
+        }
+    }
+
+    /**
+     * Allocate a new ZVAL node holding this value.
+     */
+    pub fn to_zval_node(&self, attr: php::zend_ast_attr, lineno: u32) -> ZendAst {
+        let (t, l, d, s): (u32, php::zend_long, f64, &[u8]) = match *self {
+            Value::Null => (php::IS_NULL, 0, 0.0, &[]),
+            Value::False => (php::IS_FALSE, 0, 0.0, &[]),
+            Value::True => (php::IS_TRUE, 0, 0.0, &[]),
+            Value::Long(l) => (php::IS_LONG, l, 0.0, &[]),
+            Value::Double(d) => (php::IS_DOUBLE, 0, d, &[]),
+            Value::String(ref s) => (php::IS_STRING, 0, 0.0, s),
+        };
+        unsafe {
+            ast_create_zval_value(t as u8, l, d, s.as_ptr() as *const c_char, s.len(), attr, lineno)
+        }
+    }
+
+    /**
+     * Read a literal as the parser writes it: a ZVAL node, or the constant
+     * true, false or null. Returns None for anything else.
+     */
+    pub fn from_node(zast: ZendAst) -> Option<Value> {
+        match ast::kind_of(zast) {
+            php::_zend_ast_kind::ZEND_AST_ZVAL => Value::from_zval_node(zast),
+            php::_zend_ast_kind::ZEND_AST_CONST => {
+                let name = ast::get_raw_children(zast)[0];
+                if unsafe { (*name).attr } as u32 == context::ZEND_NAME_RELATIVE {
+                    return None;
+                }
+                match Value::from_zval_node(name) {
+                    Some(Value::String(ref name)) => {
+                        let name = if name.starts_with(b"\\") { &name[1..] } else { &name[..] };
+                        if ast::names_equal(name, b"true") {
+                            Some(Value::True)
+                        } else if ast::names_equal(name, b"false") {
+                            Some(Value::False)
+                        } else if ast::names_equal(name, b"null") {
+                            Some(Value::Null)
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /**
+     * Allocate a new node holding this value as the parser would write it,
+     * with true, false and null as constants rather than ZVAL nodes.
+     */
+    pub fn to_node(&self, lineno: u32) -> ZendAst {
+        let name: &[u8] = match *self {
+            Value::Null => b"null",
+            Value::False => b"false",
+            Value::True => b"true",
+            _ => return self.to_zval_node(0, lineno),
+        };
+        let name = Value::String(name.to_vec()).to_zval_node(context::ZEND_NAME_NOT_FQ as php::zend_ast_attr, lineno);
+        create_node(php::_zend_ast_kind::ZEND_AST_CONST as u16, 0, &[name], lineno)
+    }
+}
+
+impl ::std::fmt::Display for Value {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            Value::Null => write!(f, "NULL"),
+            Value::False => write!(f, "false"),
+            Value::True => write!(f, "true"),
+            Value::Long(l) => write!(f, "{}", l),
+            Value::Double(d) => write!(f, "{:?}", d),
+            Value::String(ref s) => write!(f, "'{}'", ast::escape_bytes(s)),
+        }
+    }
+}
+
+/**
+ * Why a pattern couldn't be compiled.
+ */
+#[derive(Clone, Debug)]
+pub enum PatternError {
+    /// The pattern isn't valid PHP.
+    Syntax(ast::SyntaxError),
+    /// A PPHP::$variable has a constraint that doesn't exist.
+    InvalidConstraint { var: String, line: u32 },
+    /// The pattern contains something that can't be matched or reproduced.
+    Unsupported { what: String, line: u32 },
+    /// A replacement uses a variable that the search pattern doesn't bind.
+    Unbound { var: String },
+    /// A replacement uses a variable bound to a list somewhere a single
+    /// node is needed.
+    ListOutsideList { var: String },
+}
+
+impl PatternError {
+    /**
+     * The line of the pattern the error is on, if it's known.
+     */
+    pub fn line(&self) -> Option<u32> {
+        match *self {
+            PatternError::Syntax(ref e) => Some(e.line),
+            PatternError::InvalidConstraint { line, .. } => Some(line),
+            PatternError::Unsupported { line, .. } => Some(line),
+            PatternError::Unbound { .. } | PatternError::ListOutsideList { .. } => None,
+        }
+    }
+
+    /**
+     * The error without its line number.
+     */
+    pub fn message(&self) -> String {
+        match *self {
+            PatternError::Syntax(ref e) => e.message.clone(),
+            PatternError::InvalidConstraint { ref var, .. } => {
+                format!("invalid constraint in PPHP::${{'{}'}}", var)
+            }
+            PatternError::Unsupported { ref what, .. } => format!("{} can't be used in a pattern", what),
+            PatternError::Unbound { ref var } => {
+                format!("PPHP::${} isn't bound by the search pattern", var)
+            }
+            PatternError::ListOutsideList { ref var } => {
+                format!("PPHP::${} is bound to a list, so it can only be used in a list", var)
+            }
+        }
+    }
+}
+
+impl ::std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.line() {
+            Some(line) => write!(f, "{} on line {}", self.message(), line),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+/**
+ * The name of a declaration in a pattern: either a literal name or an
+ * identifier variable (`function PPHP__name() {}`).
+ */
+#[derive(Clone, Debug)]
+pub enum DeclName {
+    Literal(Vec<u8>),
+    Var(String),
+}
+
+/**
+ * A node in a compiled pattern. Unlike a ZendAst, this is owned by Rust and
+ * doesn't depend on any Zend allocations, so it can be kept for the lifetime
+ * of the process.
+ */
+#[derive(Clone, Debug)]
+pub enum PatternNode {
+    /// A PPHP::$variable or identifier variable. `unpack` is set for
+    /// ...PPHP::$variable, which produces an unpack node if the variable is
+    /// bound to a single node.
+    Var { param: BindParam, unpack: bool },
+    /// A literal value.
+    Value { attr: php::zend_ast_attr, value: Value },
+    /// The name of a function, class or constant, fully qualified as if the
+    /// pattern were in the global namespace. It matches any name which
+    /// resolves to the same symbol in the code it's compared against.
+    Name { kind: SymbolKind, name: Vec<u8> },
+    /// Any other node; list nodes have any number of children.
+    Node { kind: php::zend_ast_kind, attr: php::zend_ast_attr, children: Vec<Option<PatternNode>> },
+    /// A function, closure, method or class declaration.
+    Decl { kind: php::zend_ast_kind, attr: php::zend_ast_attr, flags: u32, name: DeclName, children: Vec<Option<PatternNode>> },
+}
+
+impl PatternNode {
+    /**
+     * Convert a parsed AST into a pattern. Returns an error if it contains
+     * something that can't be used in a pattern.
+     */
+    pub fn compile(zast: ZendAst) -> Result<PatternNode, PatternError> {
+        let kind = unsafe { (*zast).kind };
+        let attr = unsafe { (*zast).attr };
+        let compile_children = |children: Vec<ZendAst>| -> Result<Vec<Option<PatternNode>>, PatternError> {
+            children.into_iter().map(|child| {
+                if child.is_null() {
+                    Ok(None)
+                } else {
+                    PatternNode::compile(child).map(Some)
+                }
+            }).collect()
+        };
+
+        if kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 {
+            let value = match Value::from_zval_node(zast) {
+                Some(value) => value,
+                None => return Err(PatternError::Unsupported {
+                    what: format!("a literal of type {}", unsafe { zval_type(ast_zval(zast)) }),
+                    line: unsafe { ast_lineno(zast) },
+                }),
+            };
+            return Ok(PatternNode::Value { attr: attr, value: value });
+        }
+
+        if let Some(param) = is_bind_param(zast) {
+            return param.map(|param| PatternNode::Var { param: param, unpack: false });
+        }
+
+        if kind == php::_zend_ast_kind::ZEND_AST_UNPACK as u16 {
+            let children = ast::get_raw_children(zast);
+            if let Some(param) = is_bind_param(children[0]) {
+                return param.map(|mut param| {
+                    param.variadic = true;
+                    PatternNode::Var { param: param, unpack: true }
+                });
+            }
+        }
+
+        if unsafe { ast_is_decl(zast) } {
+            let decl = zast as ZendAstDecl;
+            let name = unsafe { ast::zend_str_bytes((*decl).name) };
+            let name = match ::std::str::from_utf8(name).ok().and_then(identifier_param_name) {
+                Some(var) => DeclName::Var(var.to_string()),
+                None => DeclName::Literal(name.to_vec()),
+            };
+            return Ok(PatternNode::Decl {
+                kind: kind,
+                attr: attr,
+                flags: unsafe { (*decl).flags },
+                name: name,
+                children: compile_children(ast::get_raw_children(zast))?,
+            });
+        }
+
+        let children = if unsafe { ast_is_list(zast) } {
+            // NULL list elements aren't significant
+            ast::get_children(zast).iter().map(|c| c.deref()).collect()
+        } else {
+            ast::get_raw_children(zast)
+        };
+        let mut children = compile_children(children)?;
+        for (index, child) in children.iter_mut().enumerate() {
+            if !is_name_position(ast::kind_of(zast), index) {
+                continue;
+            }
+            let name = match *child {
+                Some(PatternNode::Value { value: Value::String(ref s), .. }) => {
+                    ::std::str::from_utf8(s).ok().and_then(identifier_param_name).map(|name| name.to_string())
+                }
+                _ => None,
+            };
+            if let Some(name) = name {
+                // identifier variables can only bind to names
+                *child = Some(PatternNode::Var {
+                    param: BindParam {
+                        name: name,
+                        constraints: vec![Constraint::String],
+                        variadic: false,
+                    },
+                    unpack: false,
+                });
+            }
+        }
+        if let Some((index, symbol)) = name_child(ast::kind_of(zast)) {
+            let name = match children[index] {
+                Some(PatternNode::Value { attr, value: Value::String(ref name) }) => {
+                    Some(PhpContext::new().resolve(symbol, name, attr as u32).0)
+                }
+                _ => None,
+            };
+            if let Some(name) = name {
+                children[index] = Some(PatternNode::Name { kind: symbol, name: name });
+            }
+        }
+        Ok(PatternNode::Node {
+            kind: kind,
+            attr: attr,
+            children: children,
+        })
+    }
+
+    fn constrain(&mut self, name: &str, constraints: &[Constraint]) -> bool {
+        let children = match *self {
+            PatternNode::Var { ref mut param, .. } => {
+                if param.name != name {
+                    return false;
+                }
+                param.constraints.extend(constraints.iter().cloned());
+                return true;
+            }
+            PatternNode::Value { .. } | PatternNode::Name { .. } => return false,
+            PatternNode::Node { ref mut children, .. } => children,
+            PatternNode::Decl { ref mut children, .. } => children,
+        };
+        let mut found = false;
+        for child in children.iter_mut().filter_map(|c| c.as_mut()) {
+            found = child.constrain(name, constraints) || found;
+        }
+        found
+    }
+
+    /**
+     * Add the variables in this pattern to `vars`, with whether each is
+     * variadic.
+     */
+    fn variables(&self, vars: &mut Vec<(String, bool)>) {
+        let children = match *self {
+            PatternNode::Var { ref param, .. } => {
+                vars.push((param.name.clone(), param.variadic));
+                return;
+            }
+            PatternNode::Value { .. } | PatternNode::Name { .. } => return,
+            PatternNode::Node { ref children, .. } => children,
+            PatternNode::Decl { ref name, ref children, .. } => {
+                if let DeclName::Var(ref var) = *name {
+                    vars.push((var.clone(), false));
+                }
+                children
+            }
+        };
+        for child in children.iter().filter_map(|c| c.as_ref()) {
+            child.variables(vars);
+        }
+    }
+
+    /**
+     * Check that none of the variables named in `lists` is used outside a
+     * list; `in_list` is set if this node is an element of one.
+     */
+    fn check_lists(&self, lists: &[String], in_list: bool) -> Result<(), PatternError> {
+        let children = match *self {
+            PatternNode::Var { ref param, .. } => {
+                if !in_list && lists.contains(&param.name) {
+                    return Err(PatternError::ListOutsideList { var: param.name.clone() });
+                }
+                return Ok(());
+            }
+            PatternNode::Value { .. } | PatternNode::Name { .. } => return Ok(()),
+            PatternNode::Node { ref children, .. } => children,
+            PatternNode::Decl { ref children, .. } => children,
+        };
+        let is_list = self.is_list();
+        for child in children.iter().filter_map(|c| c.as_ref()) {
+            child.check_lists(lists, is_list)?;
+        }
+        Ok(())
+    }
+
+    fn is_list(&self) -> bool {
+        match *self {
+            PatternNode::Node { kind, .. } => (kind >> php::ZEND_AST_IS_LIST_SHIFT) & 1 == 1,
+            _ => false,
+        }
+    }
+
+    fn variadic_param(&self) -> Option<&BindParam> {
+        match *self {
+            PatternNode::Var { ref param, .. } if param.variadic => Some(param),
+            _ => None,
+        }
+    }
+
+    /**
+     * Unwraps statement lists containing only a single node, as
+     * ast::unwrap_all does for ZendAsts.
+     */
+    fn unwrap(&self) -> &PatternNode {
+        match *self {
+            PatternNode::Node { kind, ref children, .. }
+                    if kind == php::_zend_ast_kind::ZEND_AST_STMT_LIST as u16 && children.len() == 1 => {
+                match children[0] {
+                    Some(ref child) => child.unwrap(),
+                    None => self,
+                }
+            }
+            _ => self,
+        }
+    }
+
+    /**
+     * Returns true if `zast` matches this pattern, binding any variables.
+     * Names in `zast` are resolved in `ctx`.
+     */
+    pub fn matches(&self, zast: ZendAst, bindings: &mut Bindings, ctx: &PhpContext) -> bool {
+        // unwrap statement lists with exactly 1 child
+        let pattern = self.unwrap();
+
+        // a variadic variable on its own (e.g. as the only statement in a
+        // block) binds all of the statements it's matched against
+        if let Some(param) = pattern.variadic_param() {
+            let nodes = if unsafe { ast_is_list(zast) } {
+                ast::get_children(zast).iter().map(|child| child.deref()).collect()
+            } else {
+                vec![zast]
+            };
+            return bind_variadic(param, &nodes, bindings);
+        }
+
+        let zast = ast::unwrap_all(zast);
+        let (kind, attr) = unsafe { ((*zast).kind, (*zast).attr) };
+
+        match *pattern {
+            PatternNode::Var { ref param, .. } => bind_node(param, zast, bindings),
+            PatternNode::Value { attr: a, ref value } => {
+                kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 && attr == a &&
+                    Value::from_zval_node(zast).as_ref() == Some(value)
+            }
+            PatternNode::Name { kind: symbol, ref name } => {
+                if kind != php::_zend_ast_kind::ZEND_AST_ZVAL as u16 {
+                    return false;
+                }
+                let actual = match Value::from_zval_node(zast) {
+                    Some(Value::String(actual)) => actual,
+                    _ => return false,
+                };
+                let (resolved, fallback) = ctx.resolve(symbol, &actual, attr as u32);
+                context::symbols_equal(symbol, name, &resolved) ||
+                    fallback.map_or(false, |fallback| context::symbols_equal(symbol, name, &fallback))
+            }
+            PatternNode::Node { kind: k, attr: a, ref children } => {
+                // TODO: should attr mismatch matter for all kinds?
+                if kind != k || attr != a {
+                    return false;
+                }
+                if pattern.is_list() {
+                    let nodes: Vec<ZendAst> = ast::get_children(zast).iter().map(|c| c.deref()).collect();
+                    let patterns: Vec<&PatternNode> = children.iter().filter_map(|c| c.as_ref()).collect();
+                    match_sequence(&patterns, &nodes, bindings, ctx)
+                } else {
+                    match_children(children, &ast::get_raw_children(zast), bindings, ctx)
+                }
+            }
+            PatternNode::Decl { kind: k, attr: a, flags: f, ref name, ref children } => {
+                // the flags hold modifiers such as `final` or a by-reference
+                // return, which the replacement would otherwise change
+                kind == k && attr == a && unsafe { (*(zast as ZendAstDecl)).flags } == f &&
+                    match_children(children, &ast::get_raw_children(zast), bindings, ctx) &&
+                    match_decl_name(name, zast, bindings)
+            }
+        }
+    }
+
+    /**
+     * Build a new AST from this pattern, substituting bound variables.
+     * Created nodes are given the line number `lineno`. Returns None if a
+     * variable isn't bound, or an identifier variable isn't bound to a name.
+     */
+    pub fn instantiate(&self, bindings: &Bindings, lineno: u32) -> Option<ZendAst> {
+        Some(match *self {
+            PatternNode::Var { ref param, unpack } => {
+                // bound nodes are copied, so a variable used more than once
+                // doesn't share a node between places in the tree
+                match bindings.get(&param.name) {
+                    Some(&Binding::Node(node)) => {
+                        let node = ast::copy_tree(node, &mut |_| None);
+                        if unpack {
+                            create_node(php::_zend_ast_kind::ZEND_AST_UNPACK as u16, 0, &[node], lineno)
+                        } else {
+                            node
+                        }
+                    }
+                    Some(&Binding::List(ref nodes)) => {
+                        let nodes: Vec<ZendAst> = nodes.iter().map(|&node| ast::copy_tree(node, &mut |_| None)).collect();
+                        if nodes.len() == 1 {
+                            nodes[0]
+                        } else {
+                            // check_lists only allows this as a whole
+                            // replacement for a statement
+                            ast::create_list(php::_zend_ast_kind::ZEND_AST_STMT_LIST as u16, 0, &nodes)
+                        }
+                    }
+                    None => return None,
+                }
+            }
+            PatternNode::Value { attr, ref value } => value.to_zval_node(attr, lineno),
+            // fully qualified, so the name means the same in any namespace
+            PatternNode::Name { kind, ref name } => {
+                let attr = if kind == SymbolKind::Class && context::is_special_class(name) {
+                    context::ZEND_NAME_NOT_FQ
+                } else {
+                    context::ZEND_NAME_FQ
+                };
+                Value::String(name.clone()).to_zval_node(attr as php::zend_ast_attr, lineno)
+            }
+            PatternNode::Node { kind, attr, ref children } => {
+                if self.is_list() {
+                    // variables bound to lists are spliced into the new list
+                    let mut elements = Vec::new();
+                    for child in children.iter().filter_map(|c| c.as_ref()) {
+                        match *child {
+                            PatternNode::Var { ref param, .. } => match bindings.get(&param.name) {
+                                Some(&Binding::List(ref nodes)) => {
+                                    elements.extend(nodes.iter().map(|&node| ast::copy_tree(node, &mut |_| None)));
+                                    continue;
+                                }
+                                _ => {}
+                            },
+                            _ => {}
+                        }
+                        elements.push(child.instantiate(bindings, lineno)?);
+                    }
+                    let list = ast::create_list(kind, attr, &elements);
+                    unsafe { (*list).lineno = lineno };
+                    list
+                } else {
+                    let children = instantiate_children(children, bindings, lineno)?;
+                    create_node(kind, attr, &children, lineno)
+                }
+            }
+            PatternNode::Decl { kind, attr, flags, ref name, ref children } => {
+                let name = match *name {
+                    DeclName::Literal(ref name) => unsafe {
+                        string_init(name.as_ptr() as *const c_char, name.len())
+                    },
+                    DeclName::Var(ref var) => match bindings.get(var) {
+                        Some(&Binding::Node(node)) if is_string_node(node) => unsafe { zval_string(ast_zval(node)) },
+                        _ => return None,
+                    },
+                };
+                let c = instantiate_children(children, bindings, lineno)?;
+                unsafe {
+                    let decl = php::zend_ast_create_decl(
+                        kind, flags, lineno, ::std::ptr::null_mut(), name,
+                        c[0], c[1], c[2], c[3]
+                    );
+                    (*decl).attr = attr;
+                    decl
+                }
+            }
+        })
+    }
+
+    /**
+     * Print this pattern in the same format as ast::print_node.
+     */
+    pub fn print(&self, indentation: usize) {
+        let pattern = self.unwrap();
+        let children = match *pattern {
+            PatternNode::Var { ref param, .. } => {
+                trace!("{:width$}<{}>", " ", param, width=indentation * 2);
+                return;
+            }
+            PatternNode::Value { ref value, .. } => {
+                trace!("{:width$}ZVAL {} (0 children)", " ", value, width=indentation * 2);
+                return;
+            }
+            PatternNode::Name { kind, ref name } => {
+                trace!("{:width$}NAME {:?} {} (0 children)", " ", kind, ast::escape_bytes(name), width=indentation * 2);
+                return;
+            }
+            PatternNode::Node { kind, attr, ref children } => {
+                trace!("{:width$}{} {}({} children)", " ", ast::kind_name(kind), ast::attr_string(attr),
+                         children.iter().filter(|c| c.is_some()).count(), width=indentation * 2);
+                children
+            }
+            PatternNode::Decl { kind, attr, ref name, ref children, .. } => {
+                let name = match *name {
+                    DeclName::Literal(ref name) => ast::escape_bytes(name),
+                    DeclName::Var(ref var) => format!("<${}>", var),
+                };
+                trace!("{:width$}{} {} {}({} children)", " ", ast::kind_name(kind), name, ast::attr_string(attr),
+                         children.iter().filter(|c| c.is_some()).count(), width=indentation * 2);
+                children
+            }
+        };
+        for child in children.iter().filter_map(|c| c.as_ref()) {
+            child.print(indentation + 1);
+        }
+    }
+}
+
+/**
+ * A pattern compiled from PHP code, e.g. "is_a(PPHP::$_1, PPHP::$_2);".
+ *
+ * Patterns are compiled once, when a rule is created, and can then be matched
+ * against or used as a template for replacements any number of times.
+ */
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    pub source: String,
+    pub root: PatternNode,
+}
+
+impl Pattern {
+    /**
+     * Parse and compile a pattern. Returns an error if the code doesn't parse
+     * or can't be used as a pattern.
+     */
+    pub fn compile(code: &str) -> Result<Pattern, PatternError> {
+        let parsed = ast::parse_pattern(code).map_err(PatternError::Syntax)?;
+        PatternNode::compile(parsed.root).map(|root| Pattern {
+            source: code.to_string(),
+            root: root,
+        })
+    }
+
+    /**
+     * Returns true if `zast` matches this pattern. If variable names are
+     * encountered, the corresponding AST node will be bound to the variable.
+     * Function, class and constant names are compared after resolving them,
+     * those in `zast` against the namespace and imports in `ctx`.
+     */
+    pub fn matches(&self, zast: ZendAst, bindings: &mut Bindings, ctx: &PhpContext) -> bool {
+        self.root.matches(zast, bindings, ctx)
+    }
+
+    /**
+     * Build a new AST from this pattern, replacing all PPHP::$variable nodes
+     * with their bindings. The new nodes are given the line number of
+     * `original`, the node being replaced.
+     *
+     * Returns None if the pattern uses a variable which isn't bound, which
+     * can't happen if it was checked with check_bound.
+     */
+    pub fn instantiate(&self, bindings: &Bindings, original: ZendAst) -> Option<ZendAst> {
+        let lineno = unsafe { ast_lineno(original) };
+        let root = self.root.unwrap();
+        root.instantiate(bindings, lineno)
+    }
+
+    /**
+     * Add constraints to every occurrence of the variable `name`, as if it
+     * were written `PPHP::${'name:constraint'}`. Returns false if the
+     * variable doesn't appear in this pattern.
+     */
+    pub fn constrain(&mut self, name: &str, constraints: &[Constraint]) -> bool {
+        self.root.constrain(name, constraints)
+    }
+
+    /**
+     * The kind of node this pattern can match, or None if it can match any
+     * node (e.g. a pattern consisting of a single variable.)
+     */
+    pub fn root_kind(&self) -> Option<php::zend_ast_kind> {
+        match *self.root.unwrap() {
+            PatternNode::Var { .. } => None,
+            PatternNode::Value { .. } | PatternNode::Name { .. } => Some(php::_zend_ast_kind::ZEND_AST_ZVAL as u16),
+            PatternNode::Node { kind, .. } | PatternNode::Decl { kind, .. } => Some(kind),
+        }
+    }
+
+    /**
+     * Check that every variable used in this pattern is one of the `bound`
+     * variable names, so it can be instantiated with their bindings.
+     */
+    pub fn check_bound<'a, I>(&self, bound: I) -> Result<(), PatternError> where I: IntoIterator<Item=&'a String> {
+        let bound: Vec<&String> = bound.into_iter().collect();
+        match self.variables().into_iter().find(|var| !bound.contains(&var)) {
+            Some(var) => Err(PatternError::Unbound { var: var }),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Check that the variables named in `lists`, which are bound to lists of
+     * nodes, are only used where a list can go: among the elements of a list
+     * node, or as the whole replacement if `statement` is set because it
+     * replaces a statement, where the nodes become a block.
+     */
+    pub fn check_lists(&self, lists: &[String], statement: bool) -> Result<(), PatternError> {
+        match *self.root.unwrap() {
+            PatternNode::Var { .. } if statement => Ok(()),
+            ref root => root.check_lists(lists, false),
+        }
+    }
+
+    /**
+     * The names of the variables this pattern binds when it matches.
+     */
+    pub fn variables(&self) -> Vec<String> {
+        let mut vars = Vec::new();
+        self.root.variables(&mut vars);
+        vars.into_iter().map(|(name, _)| name).collect()
+    }
+
+    /**
+     * The names of the variadic variables this pattern binds, which are
+     * bound to lists of nodes.
+     */
+    pub fn list_variables(&self) -> Vec<String> {
+        let mut vars = Vec::new();
+        self.root.variables(&mut vars);
+        vars.into_iter().filter(|&(_, variadic)| variadic).map(|(name, _)| name).collect()
+    }
+
+    /**
+     * Returns true if this pattern only matches statements, rather than
+     * expressions.
+     */
+    pub fn is_statement(&self) -> bool {
+        self.root_kind().map_or(false, is_statement_kind)
+    }
+
+    pub fn print(&self, indentation: usize) {
+        self.root.print(indentation);
+    }
+}
+
+/**
+ * The kinds of node any of `patterns` can match, for
+ * AstOptimizationRule::root_kinds. Returns None if any of them can match any
+ * node.
+ */
+pub fn root_kinds<'a, I>(patterns: I) -> Option<Vec<php::zend_ast_kind>> where I: Iterator<Item=&'a Pattern> {
+    let mut kinds = Vec::new();
+    for pattern in patterns {
+        let kind = pattern.root_kind()?;
+        if !kinds.contains(&kind) {
+            kinds.push(kind);
+        }
+    }
+    Some(kinds)
+}
+
+/**
+ * A compiled search pattern and its replacement.
+ */
+#[derive(Clone, Debug)]
+pub struct Substitution {
+    pub search: Pattern,
+    pub replace: Pattern,
+}
+
+/**
+ * An error in one of a substitution's patterns.
+ */
+#[derive(Clone, Debug)]
+pub enum SubstitutionError {
+    Search(PatternError),
+    Replace(PatternError),
+}
+
+impl ::std::fmt::Display for SubstitutionError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            SubstitutionError::Search(ref e) => write!(f, "invalid search pattern: {}", e),
+            SubstitutionError::Replace(ref e) => write!(f, "invalid replacement pattern: {}", e),
+        }
+    }
+}
+
+impl Substitution {
+    pub fn try_create(search: &str, replace: &str) -> Result<Self, SubstitutionError> {
+        let search = Pattern::compile(search).map_err(SubstitutionError::Search)?;
+        let replace = Pattern::compile(replace).map_err(SubstitutionError::Replace)?;
+        Substitution::from_patterns(search, replace).map_err(SubstitutionError::Replace)
+    }
+
+    /**
+     * Combine compiled patterns, checking that the replacement only uses
+     * variables bound by the search pattern, and only uses variadic ones
+     * where a list can go.
+     */
+    pub fn from_patterns(search: Pattern, replace: Pattern) -> Result<Self, PatternError> {
+        replace.check_bound(&search.variables())?;
+        replace.check_lists(&search.list_variables(), search.is_statement())?;
+        Ok(Substitution {search: search, replace: replace})
+    }
+}
+
+/**
+ * If this AST node matches the form PPHP::$variableName, returns
+ * Some(parsed variable), or Some(error) if the variable's constraints are
+ * invalid; otherwise returns None.
+ */
+fn is_bind_param(zast: ZendAst) -> Option<Result<BindParam, PatternError>> {
+    if zast.is_null() || unsafe { (*zast).kind } != php::_zend_ast_kind::ZEND_AST_STATIC_PROP as u16 {
+        return None;
+    }
+    let children = ast::get_raw_children(zast);
+    if children.len() != 2 || children.iter().any(|c| c.is_null()) {
+        return None;
+    }
+    unsafe {
+        let is_string = |zast: ZendAst| {
+            (*zast).kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 &&
+                zval_type(ast_zval(zast)) as u32 == php::IS_STRING
+        };
+        if !is_string(children[0]) || !is_string(children[1]) {
+            return None;
+        }
+        if ast::zend_str_bytes(zval_str(ast_zval(children[0]))) == b"PPHP" {
+            // found it!
+            let var_name = String::from_utf8_lossy(ast::zend_str_bytes(zval_str(ast_zval(children[1]))));
+            return Some(BindParam::parse(&var_name).ok_or_else(|| PatternError::InvalidConstraint {
+                var: var_name.to_string(),
+                line: ast_lineno(zast),
+            }));
+        }
+    }
+    None
+}
+
+/**
+ * Bind a variable to a single node, or if it's already bound, check that the
+ * node matches the existing binding.
+ */
+fn bind_node(param: &BindParam, zast: ZendAst, bindings: &mut Bindings) -> bool {
+    match bindings.get(&param.name) {
+        Some(&Binding::Node(bound)) => return ast::nodes_equal(bound, zast),
+        Some(&Binding::List(_)) => return false,
+        None => {}
+    }
+    if param.accepts(zast) {
+        bindings.insert(param.name.clone(), Binding::Node(zast));
+        true
+    } else {
+        false
+    }
+}
+
+/**
+ * Bind a variadic variable to a sequence of nodes, or if it's already bound,
+ * check that the nodes match the existing binding.
+ */
+fn bind_variadic(param: &BindParam, nodes: &[ZendAst], bindings: &mut Bindings) -> bool {
+    match bindings.get(&param.name) {
+        Some(&Binding::List(ref bound)) => {
+            return bound.len() == nodes.len() &&
+                bound.iter().zip(nodes.iter()).all(|(b, n)| ast::nodes_equal(*b, *n));
+        }
+        Some(&Binding::Node(_)) => return false,
+        None => {}
+    }
+    if nodes.iter().all(|n| param.accepts(*n)) {
+        bindings.insert(param.name.clone(), Binding::List(nodes.to_vec()));
+        true
+    } else {
+        false
+    }
+}
+
+/**
+ * Match the children of a fixed-size node, including NULL children.
+ */
+fn match_children(patterns: &[Option<PatternNode>], nodes: &[ZendAst], bindings: &mut Bindings, ctx: &PhpContext) -> bool {
+    patterns.len() == nodes.len() && patterns.iter().zip(nodes.iter()).all(|(pattern, &node)| {
+        match (pattern.as_ref(), node.is_null()) {
+            (None, is_null) => is_null,
+            (Some(pattern), true) => {
+                // a list made up of variadic variables can match a missing
+                // list, e.g. the empty initializer in `for (; $i < 3; ++$i)`
+                match *pattern {
+                    PatternNode::Node { ref children, .. } if pattern.is_list() => {
+                        let patterns: Vec<&PatternNode> = children.iter().filter_map(|c| c.as_ref()).collect();
+                        match_sequence(&patterns, &[], bindings, ctx)
+                    }
+                    _ => false,
+                }
+            }
+            (Some(pattern), false) => pattern.matches(node, bindings, ctx),
+        }
+    })
+}
+
+/**
+ * Match a sequence of list elements against a sequence of patterns, any of
+ * which may be variadic. Bindings are only updated if the match succeeds.
+ */
+fn match_sequence(patterns: &[&PatternNode], nodes: &[ZendAst], bindings: &mut Bindings, ctx: &PhpContext) -> bool {
+    let (first, rest) = match patterns.split_first() {
+        Some(split) => split,
+        None => return nodes.is_empty(),
+    };
+    match first.variadic_param() {
+        Some(param) => {
+            // try the shortest possible binding first
+            for n in 0 .. nodes.len() + 1 {
+                let mut attempt = bindings.clone();
+                if bind_variadic(param, &nodes[..n], &mut attempt) &&
+                        match_sequence(rest, &nodes[n..], &mut attempt, ctx) {
+                    *bindings = attempt;
+                    return true;
+                }
+            }
+            false
+        }
+        None => {
+            if nodes.is_empty() {
+                return false;
+            }
+            let mut attempt = bindings.clone();
+            if first.matches(nodes[0], &mut attempt, ctx) &&
+                    match_sequence(rest, &nodes[1..], &mut attempt, ctx) {
+                *bindings = attempt;
+                true
+            } else {
+                false
+            }
+        }
+    }
+}
+
+/**
+ * The position of the child of a node of the given kind which names a
+ * function, class or constant, if it has one.
+ */
+pub fn name_child(kind: php::_zend_ast_kind) -> Option<(usize, SymbolKind)> {
+    use php::_zend_ast_kind::*;
+    match kind {
+        ZEND_AST_CALL => Some((0, SymbolKind::Function)),
+        ZEND_AST_CONST => Some((0, SymbolKind::Constant)),
+        ZEND_AST_NEW | ZEND_AST_STATIC_CALL | ZEND_AST_STATIC_PROP | ZEND_AST_CLASS_CONST => {
+            Some((0, SymbolKind::Class))
+        }
+        ZEND_AST_INSTANCEOF => Some((1, SymbolKind::Class)),
+        _ => None,
+    }
+}
+
+/**
+ * Returns true if child `index` of a node of kind `kind` is a name rather
+ * than an expression, so it can be an identifier variable.
+ */
+fn is_name_position(kind: php::_zend_ast_kind, index: usize) -> bool {
+    use php::_zend_ast_kind::*;
+    if name_child(kind).map_or(false, |(child, _)| child == index) {
+        return true;
+    }
+    match kind {
+        ZEND_AST_METHOD_CALL | ZEND_AST_STATIC_CALL | ZEND_AST_PROP | ZEND_AST_STATIC_PROP
+            | ZEND_AST_CLASS_CONST => index == 1,
+        ZEND_AST_CONST_ELEM => index == 0,
+        _ => false,
+    }
+}
+
+/**
+ * Returns true for the kinds of node which are statements, which can be
+ * replaced by a block.
+ */
+fn is_statement_kind(kind: php::zend_ast_kind) -> bool {
+    use php::_zend_ast_kind::*;
+    [
+        ZEND_AST_STMT_LIST, ZEND_AST_IF, ZEND_AST_SWITCH, ZEND_AST_WHILE, ZEND_AST_DO_WHILE, ZEND_AST_FOR,
+        ZEND_AST_FOREACH, ZEND_AST_TRY, ZEND_AST_DECLARE, ZEND_AST_ECHO, ZEND_AST_RETURN, ZEND_AST_UNSET,
+        ZEND_AST_GLOBAL, ZEND_AST_STATIC, ZEND_AST_CONST_DECL, ZEND_AST_THROW, ZEND_AST_BREAK,
+        ZEND_AST_CONTINUE, ZEND_AST_LABEL, ZEND_AST_GOTO, ZEND_AST_FUNC_DECL, ZEND_AST_CLASS,
+    ].iter().any(|&k| k as u16 == kind)
+}
+
+/**
+ * Compare the name of a declaration to a pattern's; if the pattern's name is
+ * an identifier variable, the declared name is bound to it as a name node,
+ * which is only created once the name is known to match.
+ */
+fn match_decl_name(name: &DeclName, zast: ZendAst, bindings: &mut Bindings) -> bool {
+    let decl_name = unsafe { (*(zast as ZendAstDecl)).name };
+    match *name {
+        DeclName::Var(ref var) => {
+            match bindings.get(var) {
+                Some(&Binding::Node(bound)) => {
+                    let decl_name = unsafe { ast::zend_str_bytes(decl_name) };
+                    return is_string_node(bound) &&
+                        unsafe { ast::zend_str_bytes(zval_string(ast_zval(bound))) } == decl_name;
+                }
+                Some(&Binding::List(_)) => return false,
+                None => {}
+            }
+            bindings.insert(var.clone(), Binding::Node(unsafe { ast_create_name(decl_name) }));
+            true
+        }
+        // function and class names are case insensitive
+        DeclName::Literal(ref name) => {
+            ast::names_equal(name, unsafe { ast::zend_str_bytes(decl_name) })
+        }
+    }
+}
+
+fn instantiate_children(children: &[Option<PatternNode>], bindings: &Bindings, lineno: u32) -> Option<Vec<ZendAst>> {
+    let mut nodes: Vec<ZendAst> = children.iter().map(|child| match *child {
+        Some(ref child) => child.instantiate(bindings, lineno),
+        None => Some(::std::ptr::null_mut()),
+    }).collect::<Option<_>>()?;
+    while nodes.len() < 4 {
+        nodes.push(::std::ptr::null_mut());
+    }
+    Some(nodes)
+}
+
+fn is_string_node(zast: ZendAst) -> bool {
+    unsafe {
+        (*zast).kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 && zval_type(ast_zval(zast)) as u32 == php::IS_STRING
+    }
+}
+
+/**
+ * Allocate a new node with up to four children.
+ */
+fn create_node(kind: php::zend_ast_kind, attr: php::zend_ast_attr, children: &[ZendAst], lineno: u32) -> ZendAst {
+    let child = |i: usize| children.get(i).cloned().unwrap_or(::std::ptr::null_mut());
+    unsafe {
+        let node = php::zend_ast_create_ex(kind, attr, child(0), child(1), child(2), child(3));
+        (*node).lineno = lineno;
+        node
+    }
+}
@@ -1,5 +1,6 @@
 #include "zend_ast.h"
 #include "zend_string.h"
+#include "zend_exceptions.h"
 #include "zend_globals.h"
 #include "zend_language_parser.h"
 #include "zend_language_scanner.h"
@@ -42,14 +43,443 @@ zend_string *zval_string(zval *zval) {
     return zval_get_string(zval);
 }
 
-zend_ast *get_ast(char *code_str) {
+/* Unlike zval_string, these don't convert or add a reference. */
+zend_long zval_long(zval *zv) {
+    return Z_LVAL_P(zv);
+}
+
+double zval_double(zval *zv) {
+    return Z_DVAL_P(zv);
+}
+
+zend_string *zval_str(zval *zv) {
+    return Z_STR_P(zv);
+}
+
+zend_string *string_init(const char *val, size_t len) {
+    return zend_string_init(val, len, 0);
+}
+
+uint32_t ast_lineno(zend_ast *ast) {
+    return zend_ast_get_lineno(ast);
+}
+
+/* Creates a ZVAL node holding a scalar of the given type. */
+zend_ast *ast_create_zval_value(zend_uchar type, zend_long lval, double dval,
+        const char *sval, size_t slen, zend_ast_attr attr, uint32_t lineno) {
+    zval zv;
+    switch (type) {
+        case IS_FALSE:
+            ZVAL_FALSE(&zv);
+            break;
+        case IS_TRUE:
+            ZVAL_TRUE(&zv);
+            break;
+        case IS_LONG:
+            ZVAL_LONG(&zv, lval);
+            break;
+        case IS_DOUBLE:
+            ZVAL_DOUBLE(&zv, dval);
+            break;
+        case IS_STRING:
+            ZVAL_STR(&zv, zend_string_init(sval, slen, 0));
+            break;
+        default:
+            ZVAL_NULL(&zv);
+            break;
+    }
+    return zend_ast_create_zval_with_lineno(&zv, attr, lineno);
+}
+
+/* Copies a ZVAL node, adding a reference to its value. */
+zend_ast *ast_copy_zval(zend_ast *ast) {
+    zval zv;
+    ZVAL_COPY(&zv, zend_ast_get_zval(ast));
+    return zend_ast_create_zval_with_lineno(&zv, ast->attr, zend_ast_get_lineno(ast));
+}
+
+/* Copies a declaration node, giving the copy the children passed in. */
+zend_ast *ast_copy_decl(zend_ast *ast, zend_ast *child0, zend_ast *child1, zend_ast *child2, zend_ast *child3) {
+    zend_ast_decl *decl = (zend_ast_decl *) ast;
+    zend_ast_decl *copy = (zend_ast_decl *) zend_ast_create_decl(ast->kind, decl->flags, decl->start_lineno,
+        decl->doc_comment ? zend_string_copy(decl->doc_comment) : NULL,
+        decl->name ? zend_string_copy(decl->name) : NULL,
+        child0, child1, child2, child3);
+    copy->end_lineno = decl->end_lineno;
+    copy->attr = decl->attr;
+    return (zend_ast *) copy;
+}
+
+/* Returns whether a call to the function named by the name node `name_ast`
+ * might take its `arg`th argument (counting from 0) by reference. Only
+ * internal functions can be known not to, since user functions may not have
+ * been declared yet. */
+zend_bool function_arg_may_be_ref(zend_ast *name_ast, uint32_t arg) {
+    zend_string *name, *lcname;
+    zend_function *fn;
+
+    if (name_ast->kind != ZEND_AST_ZVAL || Z_TYPE_P(zend_ast_get_zval(name_ast)) != IS_STRING
+            || name_ast->attr == ZEND_NAME_RELATIVE) {
+        return 1;
+    }
+    name = zend_ast_get_str(name_ast);
+    if (ZSTR_VAL(name)[0] == '\\') {
+        lcname = zend_string_alloc(ZSTR_LEN(name) - 1, 0);
+        zend_str_tolower_copy(ZSTR_VAL(lcname), ZSTR_VAL(name) + 1, ZSTR_LEN(name) - 1);
+    } else {
+        lcname = zend_string_tolower(name);
+    }
+    fn = zend_hash_find_ptr(CG(function_table), lcname);
+    zend_string_release(lcname);
+    if (fn == NULL || fn->type != ZEND_INTERNAL_FUNCTION) {
+        return 1;
+    }
+    return ARG_SHOULD_BE_SENT_BY_REF(fn, arg + 1) ? 1 : 0;
+}
+
+/* Returns what a use statement imports, given the attr of its ZEND_AST_USE,
+ * ZEND_AST_GROUP_USE or ZEND_AST_USE_ELEM node: 1 for classes, 2 for
+ * functions, 3 for constants, or 0 if that node doesn't say. */
+uint32_t use_type(zend_ast_attr attr) {
+    switch (attr) {
+        case T_CLASS:
+            return 1;
+        case T_FUNCTION:
+            return 2;
+        case T_CONST:
+            return 3;
+        default:
+            return 0;
+    }
+}
+
+/* Stores the value of a literal in `out`, or of a constant the engine can
+ * substitute at compile time: true, false, null, or a persistent constant
+ * such as PHP_INT_SIZE. Unqualified names are taken to be global constants,
+ * since the namespace isn't known here. */
+static zend_bool ast_constant_value(zend_ast *ast, zval *out) {
+    zend_ast *name_ast;
+    zend_string *name;
+    zend_constant *c;
+
+    if (ast->kind == ZEND_AST_ZVAL) {
+        if (Z_TYPE_P(zend_ast_get_zval(ast)) > IS_STRING) {
+            return 0;
+        }
+        ZVAL_COPY(out, zend_ast_get_zval(ast));
+        return 1;
+    }
+    if (ast->kind != ZEND_AST_CONST) {
+        return 0;
+    }
+    name_ast = ast->child[0];
+    name = zend_ast_get_str(name_ast);
+    if (name_ast->attr == ZEND_NAME_RELATIVE
+            || (name_ast->attr == ZEND_NAME_NOT_FQ && memchr(ZSTR_VAL(name), '\\', ZSTR_LEN(name)))) {
+        return 0;
+    }
+    if (zend_string_equals_literal_ci(name, "true")) {
+        ZVAL_TRUE(out);
+    } else if (zend_string_equals_literal_ci(name, "false")) {
+        ZVAL_FALSE(out);
+    } else if (zend_string_equals_literal_ci(name, "null")) {
+        ZVAL_NULL(out);
+    } else {
+        c = zend_hash_find_ptr(EG(zend_constants), name);
+        if (c == NULL || !(c->flags & CONST_PERSISTENT) || Z_TYPE(c->value) > IS_STRING
+                || (CG(compiler_options) & ZEND_COMPILE_NO_PERSISTENT_CONSTANT_SUBSTITUTION)) {
+            return 0;
+        }
+        ZVAL_COPY(out, &c->value);
+    }
+    return 1;
+}
+
+/* IS_LONG or IS_DOUBLE, for the type a scalar takes part in arithmetic as, or
+ * 0 for a string which isn't entirely numeric and would raise a warning. */
+static zend_uchar numeric_type(zval *op) {
+    switch (Z_TYPE_P(op)) {
+        case IS_DOUBLE:
+            return IS_DOUBLE;
+        case IS_STRING:
+            return is_numeric_string(Z_STRVAL_P(op), Z_STRLEN_P(op), NULL, NULL, 0);
+        default:
+            return IS_LONG;
+    }
+}
+
+/* Releases the result of an operator which failed or threw, and clears the
+ * exception, which would otherwise be left pending during compilation. */
+static void discard_op_result(zval *result) {
+    zval_ptr_dtor(result);
+    ZVAL_UNDEF(result);
+    zend_clear_exception();
+}
+
+static zend_bool eval_binary_op(uint32_t opcode, zval *result, zval *op1, zval *op2) {
+    zend_uchar type1 = numeric_type(op1), type2 = numeric_type(op2);
+
+    switch (opcode) {
+        case ZEND_ADD:
+        case ZEND_SUB:
+        case ZEND_MUL:
+        case ZEND_POW:
+        case ZEND_DIV:
+        case ZEND_MOD:
+        case ZEND_SL:
+        case ZEND_SR:
+            if (!type1 || !type2) {
+                return 0;
+            }
+            /* division by zero warns or throws, as do negative shifts */
+            if ((opcode == ZEND_DIV && zval_get_double(op2) == 0)
+                    || (opcode == ZEND_MOD && zval_get_long(op2) == 0)
+                    || ((opcode == ZEND_SL || opcode == ZEND_SR) && zval_get_long(op2) < 0)) {
+                return 0;
+            }
+            break;
+        case ZEND_BW_OR:
+        case ZEND_BW_AND:
+        case ZEND_BW_XOR:
+            if (!(Z_TYPE_P(op1) == IS_STRING && Z_TYPE_P(op2) == IS_STRING) && (!type1 || !type2)) {
+                return 0;
+            }
+            break;
+        case ZEND_CONCAT:
+            /* floats are converted according to the precision setting */
+            if (Z_TYPE_P(op1) == IS_DOUBLE || Z_TYPE_P(op2) == IS_DOUBLE) {
+                return 0;
+            }
+            break;
+        case ZEND_IS_IDENTICAL:
+        case ZEND_IS_NOT_IDENTICAL:
+        case ZEND_IS_EQUAL:
+        case ZEND_IS_NOT_EQUAL:
+        case ZEND_IS_SMALLER:
+        case ZEND_IS_SMALLER_OR_EQUAL:
+        case ZEND_SPACESHIP:
+        case ZEND_BOOL_XOR:
+            break;
+        default:
+            return 0;
+    }
+
+    if (get_binary_op(opcode)(result, op1, op2) == FAILURE || EG(exception)) {
+        discard_op_result(result);
+        return 0;
+    }
+    if (Z_TYPE_P(result) == IS_DOUBLE) {
+        if (!zend_finite(Z_DVAL_P(result))) {
+            return 0;
+        }
+        /* integer overflow */
+        if ((opcode == ZEND_ADD || opcode == ZEND_SUB || opcode == ZEND_MUL
+                || (opcode == ZEND_POW && zval_get_long(op2) >= 0))
+                && type1 == IS_LONG && type2 == IS_LONG) {
+            return 0;
+        }
+    }
+    return 1;
+}
+
+static zend_bool eval_unary_op(uint32_t opcode, zval *result, zval *op) {
+    switch (opcode) {
+        case ZEND_BOOL_NOT:
+            ZVAL_BOOL(result, !zend_is_true(op));
+            return 1;
+        case ZEND_BW_NOT:
+            /* throws for other types */
+            if (Z_TYPE_P(op) != IS_LONG && Z_TYPE_P(op) != IS_DOUBLE && Z_TYPE_P(op) != IS_STRING) {
+                return 0;
+            }
+            if (get_unary_op(opcode)(result, op) == FAILURE || EG(exception)) {
+                discard_op_result(result);
+                return 0;
+            }
+            return 1;
+        default:
+            return 0;
+    }
+}
+
+static zend_bool eval_cast(uint32_t type, zval *result, zval *op) {
+    switch (type) {
+        case IS_NULL:
+            ZVAL_NULL(result);
+            return 1;
+        case _IS_BOOL:
+            ZVAL_BOOL(result, zend_is_true(op));
+            return 1;
+        case IS_LONG:
+            ZVAL_LONG(result, zval_get_long(op));
+            return 1;
+        case IS_DOUBLE:
+            ZVAL_DOUBLE(result, zval_get_double(op));
+            return 1;
+        case IS_STRING:
+            if (Z_TYPE_P(op) == IS_DOUBLE) {
+                return 0;
+            }
+            ZVAL_STR(result, zval_get_string(op));
+            return 1;
+        default:
+            return 0;
+    }
+}
+
+/* Creates a node holding `value`. Booleans and null become the constants
+ * true, false and null, as they're written in code. */
+static zend_ast *ast_create_value(zval *value, uint32_t lineno) {
+    zend_ast *ast;
+    zval name;
+
+    if (Z_TYPE_P(value) == IS_NULL || Z_TYPE_P(value) == IS_FALSE || Z_TYPE_P(value) == IS_TRUE) {
+        ZVAL_STRING(&name, Z_TYPE_P(value) == IS_NULL ? "null" : (Z_TYPE_P(value) == IS_TRUE ? "true" : "false"));
+        ast = zend_ast_create(ZEND_AST_CONST, zend_ast_create_zval_with_lineno(&name, ZEND_NAME_NOT_FQ, lineno));
+        ast->lineno = lineno;
+        return ast;
+    }
+    return zend_ast_create_zval_with_lineno(value, 0, lineno);
+}
+
+/* Creates a node holding the value of the constant `name`, if it's defined
+ * with a scalar value and the engine would substitute it at compile time
+ * itself, or returns NULL. Constants which aren't persistent are defined by
+ * code that has already run, so this is only done if the compiled code won't
+ * be cached for other requests. */
+zend_ast *ast_defined_constant(const char *name, size_t name_len, uint32_t lineno) {
+    zend_constant *c = zend_hash_str_find_ptr(EG(zend_constants), name, name_len);
+    zval value;
+
+    if (c == NULL || Z_TYPE(c->value) > IS_STRING) {
+        return NULL;
+    }
+    if ((c->flags & CONST_PERSISTENT)
+            ? (CG(compiler_options) & ZEND_COMPILE_NO_PERSISTENT_CONSTANT_SUBSTITUTION)
+            : (CG(compiler_options) & ZEND_COMPILE_NO_CONSTANT_SUBSTITUTION)) {
+        return NULL;
+    }
+    /* the node takes over the reference */
+    ZVAL_COPY(&value, &c->value);
+    return ast_create_value(&value, lineno);
+}
+
+/* Evaluates an operator or cast whose operands are literals or constants,
+ * using the engine's own operator functions, storing the result in
+ * `result`. Returns 0 if the operands aren't constant, or the result can't be
+ * computed at compile time without changing what the code does: if it would
+ * warn or throw (e.g. dividing by zero, or arithmetic on a non-numeric
+ * string), overflow, or depend on a setting such as precision. */
+static zend_bool ast_eval(zend_ast *ast, zval *result) {
+    zval op1, op2;
+    zend_bool folded = 0;
+
+    ZVAL_UNDEF(&op1);
+    ZVAL_UNDEF(&op2);
+    ZVAL_UNDEF(result);
+    if (!ast_constant_value(ast->child[0], &op1)
+            || (zend_ast_get_num_children(ast) > 1 && !ast_constant_value(ast->child[1], &op2))) {
+        goto done;
+    }
+    switch (ast->kind) {
+        case ZEND_AST_BINARY_OP:
+            folded = eval_binary_op(ast->attr, result, &op1, &op2);
+            break;
+        case ZEND_AST_GREATER:
+            folded = eval_binary_op(ZEND_IS_SMALLER, result, &op2, &op1);
+            break;
+        case ZEND_AST_GREATER_EQUAL:
+            folded = eval_binary_op(ZEND_IS_SMALLER_OR_EQUAL, result, &op2, &op1);
+            break;
+        case ZEND_AST_UNARY_PLUS:
+        case ZEND_AST_UNARY_MINUS:
+            /* compiled as multiplication by 1 or -1 */
+            ZVAL_LONG(&op2, ast->kind == ZEND_AST_UNARY_PLUS ? 1 : -1);
+            folded = eval_binary_op(ZEND_MUL, result, &op1, &op2);
+            break;
+        case ZEND_AST_UNARY_OP:
+            folded = eval_unary_op(ast->attr, result, &op1);
+            break;
+        case ZEND_AST_CAST:
+            folded = eval_cast(ast->attr, result, &op1);
+            break;
+    }
+
+done:
+    zval_ptr_dtor(&op1);
+    zval_ptr_dtor(&op2);
+    if (!folded) {
+        zval_ptr_dtor(result);
+    }
+    return folded;
+}
+
+/* Returns a new node holding the value of the operator or cast `ast`, or NULL
+ * if it can't be evaluated; see ast_eval. */
+zend_ast *ast_fold(zend_ast *ast) {
+    zval result;
+
+    if (!ast_eval(ast, &result)) {
+        return NULL;
+    }
+    return ast_create_value(&result, zend_ast_get_lineno(ast));
+}
+
+/* Whether ast_fold would succeed, in which case evaluating `ast` has no side
+ * effects. */
+zend_bool ast_is_foldable(zend_ast *ast) {
+    zval result;
+
+    if (!ast_eval(ast, &result)) {
+        return 0;
+    }
+    zval_ptr_dtor(&result);
+    return 1;
+}
+
+/* Creates an unqualified name node, as found in e.g. a function call. */
+zend_ast *ast_create_name(zend_string *name) {
+    zval zv;
+    ZVAL_STR_COPY(&zv, name);
+    return zend_ast_create_zval_ex(&zv, ZEND_NAME_NOT_FQ);
+}
+
+void string_release(zend_string *str) {
+    zend_string_release(str);
+}
+
+/* The parser reports errors by throwing a ParseError; this takes its message
+ * and line, and clears it so it doesn't escape into the running script. */
+static void take_parse_error(zend_string **error_out, uint32_t *error_line_out) {
+    zval ex, rv, *prop;
+    zend_class_entry *base;
+
+    *error_out = NULL;
+    *error_line_out = 0;
+    if (!EG(exception)) {
+        return;
+    }
+    ZVAL_OBJ(&ex, EG(exception));
+    base = zend_get_exception_base(&ex);
+    prop = zend_read_property(base, &ex, "message", sizeof("message") - 1, 1, &rv);
+    *error_out = zval_get_string(prop);
+    prop = zend_read_property(base, &ex, "line", sizeof("line") - 1, 1, &rv);
+    *error_line_out = (uint32_t) zval_get_long(prop);
+    zend_clear_exception();
+}
+
+/* Parses code into an AST allocated in a new arena, which is returned in
+ * arena_out; the caller is responsible for freeing both with free_ast. If the
+ * code doesn't parse, returns NULL with the parser's message in error_out (to
+ * be released by the caller) and its line in error_line_out. */
+zend_ast *get_ast(char *code_str, size_t code_len, zend_arena **arena_out, zend_string **error_out, uint32_t *error_line_out) {
     zval code_zv;
     zend_bool original_in_compilation;
     zend_lex_state original_lex_state;
-    zend_ast *ast;
+    zend_ast *ast = NULL;
 
     zend_string *code;
-    code = zend_string_init(code_str, strlen(code_str), 0);
+    code = zend_string_init(code_str, code_len, 0);
 
     char *filename;
     filename = "<pphp>";
@@ -69,11 +499,13 @@ zend_ast *get_ast(char *code_str) {
             zend_ast_destroy(CG(ast));
             zend_arena_destroy(CG(ast_arena));
             CG(ast) = NULL;
+            take_parse_error(error_out, error_line_out);
         }
-    }
 
-    /* restore_lexical_state changes CG(ast) and CG(ast_arena) */
-    ast = CG(ast);
+        /* restore_lexical_state changes CG(ast) and CG(ast_arena) */
+        ast = CG(ast);
+    }
+    *arena_out = ast ? CG(ast_arena) : NULL;
 
     zend_restore_lexical_state(&original_lex_state);
     CG(in_compilation) = original_in_compilation;
@@ -83,6 +515,11 @@ zend_ast *get_ast(char *code_str) {
     return ast;
 }
 
+void free_ast(zend_ast *ast, zend_arena *arena) {
+    zend_ast_destroy(ast);
+    zend_arena_destroy(arena);
+}
+
 void set_ast(zend_ast *ast) {
     CG(ast) = ast;
 }
@@ -105,3 +542,354 @@ zend_string *var_export(zval *v) {
     smart_str_0(&buf);
     return smart_str_extract(&buf);
 }
+
+zend_uchar zval_type(zval *zv) {
+    return Z_TYPE_P(zv);
+}
+
+zval *callable_copy(zval *callable) {
+    zval *copy = emalloc(sizeof(zval));
+    ZVAL_COPY(copy, callable);
+    return copy;
+}
+
+void callable_free(zval *callable) {
+    zval_ptr_dtor(callable);
+    efree(callable);
+}
+
+zval *bindings_array_new() {
+    zval *arr = emalloc(sizeof(zval));
+    array_init(arr);
+    return arr;
+}
+
+void bindings_array_free(zval *arr) {
+    zval_ptr_dtor(arr);
+    efree(arr);
+}
+
+/* Adds a nested array to arr and returns it. */
+zval *bindings_array_add_array(zval *arr, const char *name, size_t name_len) {
+    zval list;
+    array_init(&list);
+    return zend_hash_str_update(Z_ARRVAL_P(arr), name, name_len, &list);
+}
+
+/* Adds the source of ast to arr under the given name, or appends it if the
+ * name is NULL. */
+void bindings_array_add_ast(zval *arr, const char *name, size_t name_len, zend_ast *ast) {
+    zend_string *src = zend_ast_export("", ast, "");
+    if (name) {
+        add_assoc_str_ex(arr, name, name_len, src);
+    } else {
+        add_next_index_str(arr, src);
+    }
+}
+
+/* Returned by call_guard and call_rewrite if the callable bailed out, e.g.
+ * with a fatal error. The bailout is caught, so that the caller can release
+ * what it holds before re-raising it with zend_bailout(). */
+#define CALL_BAILOUT -2
+
+/* Calls a callable, catching a bailout. Returns SUCCESS, FAILURE or
+ * CALL_BAILOUT; retval is only set on success. */
+static int call_user_function_try(zval *callable, zval *retval, uint32_t argc, zval *args) {
+    volatile int result = FAILURE;
+
+    zend_try {
+        result = call_user_function(EG(function_table), NULL, callable, retval, argc, args);
+    } zend_catch {
+        result = CALL_BAILOUT;
+    } zend_end_try();
+    return result;
+}
+
+/* Calls a guard callable with a single array argument. Returns 1 if the guard
+ * accepted, 0 if it rejected, -1 if it couldn't be called or threw and
+ * CALL_BAILOUT if it bailed out. */
+int call_guard(zval *callable, zval *arg) {
+    zval retval;
+    int result;
+
+    result = call_user_function_try(callable, &retval, 1, arg);
+    if (result == CALL_BAILOUT) {
+        return CALL_BAILOUT;
+    }
+    if (result != SUCCESS) {
+        return -1;
+    }
+    if (EG(exception)) {
+        zend_clear_exception();
+        zval_ptr_dtor(&retval);
+        return -1;
+    }
+    result = zend_is_true(&retval);
+    zval_ptr_dtor(&retval);
+    return result;
+}
+
+/* Convert an AST into a nested PHP array:
+ *
+ *   ['kind' => int, 'attr' => int, 'lineno' => int, 'children' => [...]]
+ *
+ * ZVAL nodes have a 'value' instead of 'children', and declarations have
+ * additional 'flags', 'name' and 'doc_comment' keys. */
+void ast_to_array(zend_ast *ast, zval *out) {
+    uint32_t i, count;
+    zend_ast **children;
+    zval child_arr;
+
+    if (ast == NULL) {
+        ZVAL_NULL(out);
+        return;
+    }
+
+    array_init(out);
+    add_assoc_long(out, "kind", ast->kind);
+    add_assoc_long(out, "attr", ast->attr);
+    add_assoc_long(out, "lineno", zend_ast_get_lineno(ast));
+
+    if (ast->kind == ZEND_AST_ZVAL) {
+        zval *value = zend_ast_get_zval(ast);
+        Z_TRY_ADDREF_P(value);
+        add_assoc_zval(out, "value", value);
+        return;
+    }
+
+    if (ast_is_decl(ast)) {
+        zend_ast_decl *decl = (zend_ast_decl *) ast;
+        add_assoc_long(out, "flags", decl->flags);
+        add_assoc_str(out, "name", zend_string_copy(decl->name));
+        if (decl->doc_comment) {
+            add_assoc_str(out, "doc_comment", zend_string_copy(decl->doc_comment));
+        } else {
+            add_assoc_null(out, "doc_comment");
+        }
+        children = decl->child;
+        count = 4;
+    } else if (ast_is_list(ast)) {
+        zend_ast_list *list = zend_ast_get_list(ast);
+        children = list->child;
+        count = list->children;
+    } else {
+        children = ast->child;
+        count = ast_num_children(ast);
+    }
+
+    array_init(&child_arr);
+    for (i = 0; i < count; ++i) {
+        zval child;
+        ast_to_array(children[i], &child);
+        add_next_index_zval(&child_arr, &child);
+    }
+    add_assoc_zval(out, "children", &child_arr);
+}
+
+static zend_long array_get_long(HashTable *ht, const char *key, zend_long def) {
+    zval *v = zend_hash_str_find(ht, key, strlen(key));
+    return v ? zval_get_long(v) : def;
+}
+
+/* Whether a value can be the value of a ZVAL node: the compiler and opcache
+ * can only keep scalars, and arrays of them. */
+static zend_bool is_literal_value(zval *value) {
+    zval *element;
+
+    switch (Z_TYPE_P(value)) {
+        case IS_NULL:
+        case IS_FALSE:
+        case IS_TRUE:
+        case IS_LONG:
+        case IS_DOUBLE:
+        case IS_STRING:
+            return 1;
+        case IS_ARRAY:
+            ZEND_HASH_FOREACH_VAL(Z_ARRVAL_P(value), element) {
+                if (!is_literal_value(element)) {
+                    return 0;
+                }
+            } ZEND_HASH_FOREACH_END();
+            return 1;
+        default:
+            return 0;
+    }
+}
+
+/* The inverse of ast_to_array. New nodes are allocated in the current AST
+ * arena. Returns NULL if the array isn't a valid AST, including if a ZVAL
+ * node's value isn't a literal. */
+zend_ast *array_to_ast(zval *arr, zend_bool *valid) {
+    HashTable *ht;
+    zval *children_zv, *child_zv;
+    zend_ast *children[4] = {NULL, NULL, NULL, NULL};
+    zend_ast *ast;
+    zend_ast_kind kind;
+    zend_ast_attr attr;
+    uint32_t i = 0;
+
+    if (Z_TYPE_P(arr) == IS_NULL) {
+        return NULL;
+    }
+    if (Z_TYPE_P(arr) != IS_ARRAY || !zend_hash_str_exists(Z_ARRVAL_P(arr), "kind", sizeof("kind") - 1)) {
+        *valid = 0;
+        return NULL;
+    }
+
+    ht = Z_ARRVAL_P(arr);
+    kind = (zend_ast_kind) array_get_long(ht, "kind", 0);
+    attr = (zend_ast_attr) array_get_long(ht, "attr", 0);
+
+    if (kind == ZEND_AST_ZVAL) {
+        zval *value = zend_hash_str_find(ht, "value", sizeof("value") - 1);
+        zval copy;
+        if (!value || !is_literal_value(value)) {
+            *valid = 0;
+            return NULL;
+        }
+        ZVAL_COPY(&copy, value);
+        return zend_ast_create_zval_ex(&copy, attr);
+    }
+
+    children_zv = zend_hash_str_find(ht, "children", sizeof("children") - 1);
+    if (children_zv && Z_TYPE_P(children_zv) != IS_ARRAY) {
+        *valid = 0;
+        return NULL;
+    }
+
+    if ((kind >> ZEND_AST_IS_LIST_SHIFT) & 1) {
+        ast = zend_ast_create_list(0, kind);
+        ast->attr = attr;
+        if (children_zv) {
+            ZEND_HASH_FOREACH_VAL(Z_ARRVAL_P(children_zv), child_zv) {
+                zend_ast *child = array_to_ast(child_zv, valid);
+                if (!*valid) {
+                    return NULL;
+                }
+                ast = zend_ast_list_add(ast, child);
+            } ZEND_HASH_FOREACH_END();
+        }
+        return ast;
+    }
+
+    if (children_zv) {
+        ZEND_HASH_FOREACH_VAL(Z_ARRVAL_P(children_zv), child_zv) {
+            if (i >= 4) {
+                *valid = 0;
+                return NULL;
+            }
+            children[i++] = array_to_ast(child_zv, valid);
+            if (!*valid) {
+                return NULL;
+            }
+        } ZEND_HASH_FOREACH_END();
+    }
+
+    if (kind == ZEND_AST_FUNC_DECL || kind == ZEND_AST_CLOSURE
+            || kind == ZEND_AST_METHOD || kind == ZEND_AST_CLASS) {
+        zval *name = zend_hash_str_find(ht, "name", sizeof("name") - 1);
+        zval *doc_comment = zend_hash_str_find(ht, "doc_comment", sizeof("doc_comment") - 1);
+        if (!name || Z_TYPE_P(name) != IS_STRING) {
+            *valid = 0;
+            return NULL;
+        }
+        ast = zend_ast_create_decl(kind, (uint32_t) array_get_long(ht, "flags", 0),
+            (uint32_t) array_get_long(ht, "lineno", CG(zend_lineno)),
+            doc_comment && Z_TYPE_P(doc_comment) == IS_STRING ? zend_string_copy(Z_STR_P(doc_comment)) : NULL,
+            zend_string_copy(Z_STR_P(name)),
+            children[0], children[1], children[2], children[3]);
+        ast->attr = attr;
+        return ast;
+    }
+
+    if (i != (uint32_t) (kind >> ZEND_AST_NUM_CHILDREN_SHIFT)) {
+        *valid = 0;
+        return NULL;
+    }
+    return zend_ast_create_ex(kind, attr, children[0], children[1], children[2], children[3]);
+}
+
+void bindings_array_add_tree(zval *arr, const char *name, size_t name_len, zend_ast *ast) {
+    zval tree;
+    ast_to_array(ast, &tree);
+    if (name) {
+        add_assoc_zval_ex(arr, name, name_len, &tree);
+    } else {
+        add_next_index_zval(arr, &tree);
+    }
+}
+
+/* Calls a rewrite callable with the matched node (as an array) and the
+ * bindings array. Returns -1 if the callable failed or returned something
+ * invalid, 0 if it declined to rewrite the node, 1 if it returned a
+ * replacement, in which case either *result_ast or *result_src is set, or
+ * CALL_BAILOUT if it bailed out. */
+int call_rewrite(zval *callable, zend_ast *node, zval *bindings, zend_ast **result_ast, zend_string **result_src) {
+    zval args[2];
+    zval retval;
+    zend_bool valid = 1;
+    int result = 1;
+
+    ast_to_array(node, &args[0]);
+    ZVAL_COPY_VALUE(&args[1], bindings);
+
+    result = call_user_function_try(callable, &retval, 2, args);
+    if (result != SUCCESS) {
+        zval_ptr_dtor(&args[0]);
+        return result == CALL_BAILOUT ? CALL_BAILOUT : -1;
+    }
+    result = 1;
+    zval_ptr_dtor(&args[0]);
+    if (EG(exception)) {
+        zend_clear_exception();
+        zval_ptr_dtor(&retval);
+        return -1;
+    }
+
+    switch (Z_TYPE(retval)) {
+        case IS_NULL:
+        case IS_FALSE:
+            result = 0;
+            break;
+        case IS_STRING:
+            *result_src = zend_string_copy(Z_STR(retval));
+            break;
+        case IS_ARRAY:
+            *result_ast = array_to_ast(&retval, &valid);
+            if (!valid || !*result_ast) {
+                result = -1;
+            }
+            break;
+        default:
+            result = -1;
+    }
+    zval_ptr_dtor(&retval);
+    return result;
+}
+
+/* Appends ['name' => ..., 'description' => ..., 'priority' => ...,
+ * 'enabled' => ..., 'persistent' => ..., 'level' => ..., 'safety' => ...] to
+ * arr, for pphp_list_rules. Custom rules have no level (-1) or safety (NULL),
+ * which are listed as null. */
+void rules_array_add(zval *arr, const char *name, size_t name_len,
+        const char *description, size_t description_len, zend_long priority,
+        zend_bool enabled, zend_bool persistent, zend_long level, const char *safety) {
+    zval rule;
+    array_init(&rule);
+    add_assoc_stringl(&rule, "name", (char *) name, name_len);
+    add_assoc_stringl(&rule, "description", (char *) description, description_len);
+    add_assoc_long(&rule, "priority", priority);
+    add_assoc_bool(&rule, "enabled", enabled);
+    add_assoc_bool(&rule, "persistent", persistent);
+    if (level >= 0) {
+        add_assoc_long(&rule, "level", level);
+    } else {
+        add_assoc_null(&rule, "level");
+    }
+    if (safety) {
+        add_assoc_string(&rule, "safety", (char *) safety);
+    } else {
+        add_assoc_null(&rule, "safety");
+    }
+    add_next_index_zval(arr, &rule);
+}
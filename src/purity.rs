@@ -0,0 +1,96 @@
+use ast::{self, ZendAst};
+use context::ZEND_NAME_RELATIVE;
+use pattern::Value;
+use php;
+use php::_zend_ast_kind::*;
+
+extern "C" {
+    fn ast_is_foldable(zast: ZendAst) -> php::zend_bool;
+}
+
+// opcodes used as the attr of operator nodes, from zend_vm_opcodes.h
+const ZEND_BOOL_NOT: u32 = 13;
+const ZEND_BOOL_XOR: u32 = 14;
+const ZEND_IS_IDENTICAL: u32 = 15;
+const ZEND_IS_NOT_IDENTICAL: u32 = 16;
+
+/**
+ * Built-in functions which only look at the type of their argument, so they
+ * can't have side effects whatever it is.
+ */
+const PURE_FUNCTIONS: [&str; 15] = [
+    "gettype", "is_array", "is_bool", "is_double", "is_float", "is_int",
+    "is_integer", "is_iterable", "is_long", "is_null", "is_numeric",
+    "is_object", "is_resource", "is_scalar", "is_string",
+];
+
+/**
+ * Returns true if evaluating the expression `zast` provably has no side
+ * effects: it doesn't assign anything, call user code, raise an error or
+ * throw, so it can be removed if its value isn't used. The one exception is
+ * reading an undefined variable or constant, which raises a notice.
+ *
+ * This is conservative. For example, `$a . $b` isn't pure, because $a could
+ * be an object whose __toString() has side effects. Calls to the functions in
+ * PURE_FUNCTIONS are assumed not to have been shadowed by a namespaced
+ * function.
+ */
+pub fn is_pure(zast: ZendAst) -> bool {
+    if zast.is_null() {
+        return true;
+    }
+    let attr = unsafe { (*zast).attr } as u32;
+    let children = ast::get_raw_children(zast);
+    let all_pure = || children.iter().all(|&child| is_pure(child));
+    match ast::kind_of(zast) {
+        ZEND_AST_ZVAL | ZEND_AST_CONST | ZEND_AST_MAGIC_CONST => true,
+        ZEND_AST_VAR => is_simple_var(zast),
+        ZEND_AST_ISSET | ZEND_AST_EMPTY => is_simple_var(children[0]),
+        // converting to bool never calls user code
+        ZEND_AST_AND | ZEND_AST_OR | ZEND_AST_CONDITIONAL | ZEND_AST_COALESCE => all_pure(),
+        ZEND_AST_UNARY_OP if attr == ZEND_BOOL_NOT => all_pure(),
+        ZEND_AST_CAST if attr == php::_IS_BOOL || attr == php::IS_NULL => all_pure(),
+        ZEND_AST_BINARY_OP if attr == ZEND_IS_IDENTICAL || attr == ZEND_IS_NOT_IDENTICAL ||
+            attr == ZEND_BOOL_XOR => all_pure(),
+        // other operators are only known to be pure on constant operands
+        ZEND_AST_BINARY_OP | ZEND_AST_GREATER | ZEND_AST_GREATER_EQUAL | ZEND_AST_UNARY_OP |
+        ZEND_AST_UNARY_PLUS | ZEND_AST_UNARY_MINUS | ZEND_AST_CAST => unsafe { ast_is_foldable(zast) != 0 },
+        ZEND_AST_ARRAY => children.iter().all(|&elem| {
+            // `[&$x]` creates $x, and keys can be invalid
+            elem.is_null() || unsafe { (*elem).attr } == 0 && {
+                let parts = ast::get_raw_children(elem);
+                is_pure(parts[0]) && (parts[1].is_null() || ast::kind_of(parts[1]) == ZEND_AST_ZVAL)
+            }
+        }),
+        ZEND_AST_CALL => is_pure_call(&children),
+        _ => false,
+    }
+}
+
+/**
+ * A plain variable such as `$foo`, rather than `$$foo`.
+ */
+fn is_simple_var(zast: ZendAst) -> bool {
+    ast::kind_of(zast) == ZEND_AST_VAR && ast::kind_of(ast::get_raw_children(zast)[0]) == ZEND_AST_ZVAL
+}
+
+/**
+ * A call to one of PURE_FUNCTIONS with a single pure argument, given the
+ * children of the ZEND_AST_CALL.
+ */
+fn is_pure_call(children: &[ZendAst]) -> bool {
+    let (name, args) = (children[0], children[1]);
+    if ast::kind_of(name) != ZEND_AST_ZVAL || unsafe { (*name).attr } as u32 == ZEND_NAME_RELATIVE {
+        return false;
+    }
+    let name = match Value::from_zval_node(name) {
+        Some(Value::String(name)) => name,
+        _ => return false,
+    };
+    let name = if name.starts_with(b"\\") { &name[1..] } else { &name[..] };
+    if !PURE_FUNCTIONS.iter().any(|f| ast::names_equal(f.as_bytes(), name)) {
+        return false;
+    }
+    let args = ast::get_raw_children(args);
+    args.len() == 1 && ast::kind_of(args[0]) != ZEND_AST_UNPACK && is_pure(args[0])
+}
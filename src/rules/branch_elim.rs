@@ -1,27 +1,37 @@
 use ast;
 use context::PhpContext;
+use pattern::{self, Substitution, SubstitutionError};
+use php;
 use rules::AstOptimizationRule;
 
 #[derive(Debug)]
-pub struct BranchElimination;
+pub struct BranchElimination {
+    substitutions: Vec<Substitution>,
+}
 
 impl BranchElimination {
-    pub fn new() -> Self {
-        BranchElimination
+    pub fn new() -> Result<Self, SubstitutionError> {
+        Ok(BranchElimination {
+            substitutions: vec![
+                Substitution::try_create("if (true) { PPHP::$_1; }", "PPHP::$_1;")?,
+                Substitution::try_create("if (false) { PPHP::$_1; }", "{}")?,
+            ],
+        })
     }
 }
 
 impl AstOptimizationRule for BranchElimination {
     fn optimize(&self, ast: &mut ast::ZendAstPtr, ctx: &mut PhpContext) {
-        map_sub!(
-            "if (true) { PPHP::$_1; }",
-            "PPHP::$_1;",
-            ast
-        );
-        map_sub!(
-            "if (false) { PPHP::$_1; }",
-            "{}",
-            ast
-        );
+        for sub in self.substitutions.iter() {
+            map_sub!(&sub.search, &sub.replace, ast, ctx);
+        }
+    }
+
+    fn root_kinds(&self) -> Option<Vec<php::zend_ast_kind>> {
+        pattern::root_kinds(self.substitutions.iter().map(|sub| &sub.search))
+    }
+
+    fn describe(&self) -> String {
+        "Removes if statements with a constant condition".to_string()
     }
 }
@@ -1,57 +1,78 @@
 use ast;
 use context::PhpContext;
+use pattern::{self, Binding, Bindings, Substitution, SubstitutionError};
+use php;
+use purity;
 use rules::AstOptimizationRule;
 
 #[derive(Debug)]
-pub struct ConditionalElimination;
+pub struct ConditionalElimination {
+    substitutions: Vec<Substitution>,
+    /// Used when the operand which would be dropped may have side effects;
+    /// it's moved into a statement before the `if` instead.
+    hoists: Vec<Substitution>,
+}
 
 impl ConditionalElimination {
-    pub fn new() -> Self {
-        ConditionalElimination
+    pub fn new() -> Result<Self, SubstitutionError> {
+        Ok(ConditionalElimination {
+            substitutions: vec![
+                Substitution::try_create("true && PPHP::$_1;", "(bool) PPHP::$_1;")?,
+                Substitution::try_create("PPHP::$_1 && true;", "(bool) PPHP::$_1;")?,
+                Substitution::try_create("true || PPHP::$_1;", "true;")?,
+                Substitution::try_create("PPHP::${'_1:pure'} || true;", "true;")?,
+                Substitution::try_create("false && PPHP::$_1;", "false;")?,
+                Substitution::try_create("PPHP::${'_1:pure'} && false;", "false;")?,
+                Substitution::try_create("false || PPHP::$_1;", "(bool) PPHP::$_1;")?,
+                Substitution::try_create("PPHP::$_1 || false;", "(bool) PPHP::$_1;")?,
+            ],
+            hoists: vec![
+                Substitution::try_create(
+                    "if (PPHP::$_1 && false) { PPHP::${'...then'}; }",
+                    "PPHP::$_1; if (false) { PPHP::${'...then'}; }"
+                )?,
+                Substitution::try_create(
+                    "if (PPHP::$_1 && false) { PPHP::${'...then'}; } else { PPHP::${'...else'}; }",
+                    "PPHP::$_1; if (false) { PPHP::${'...then'}; } else { PPHP::${'...else'}; }"
+                )?,
+                Substitution::try_create(
+                    "if (PPHP::$_1 || true) { PPHP::${'...then'}; }",
+                    "PPHP::$_1; if (true) { PPHP::${'...then'}; }"
+                )?,
+                Substitution::try_create(
+                    "if (PPHP::$_1 || true) { PPHP::${'...then'}; } else { PPHP::${'...else'}; }",
+                    "PPHP::$_1; if (true) { PPHP::${'...then'}; } else { PPHP::${'...else'}; }"
+                )?,
+            ],
+        })
+    }
+}
+
+/**
+ * Pure operands are dropped by the substitutions instead.
+ */
+fn impure_operand(bindings: &Bindings) -> bool {
+    match bindings.get("_1") {
+        Some(&Binding::Node(node)) => !purity::is_pure(node),
+        _ => false,
     }
 }
 
 impl AstOptimizationRule for ConditionalElimination {
     fn optimize(&self, ast: &mut ast::ZendAstPtr, ctx: &mut PhpContext) {
-        map_sub!(
-            "true && PPHP::$_1;",
-            "PPHP::$_1;",
-            ast
-        );
-        map_sub!(
-            "PPHP::$_1 && true;",
-            "PPHP::$_1;",
-            ast
-        );
-        map_sub!(
-            "true || PPHP::$_1;",
-            "true;",
-            ast
-        );
-        map_sub!(
-            "PPHP::$_1 || true;",
-            "true;",
-            ast
-        );
-        map_sub!(
-            "false && PPHP::$_1;",
-            "false;",
-            ast
-        );
-        map_sub!(
-            "PPHP::$_1 && false;",
-            "false;",
-            ast
-        );
-        map_sub!(
-            "false || PPHP::$_1;",
-            "PPHP::$_1;",
-            ast
-        );
-        map_sub!(
-            "PPHP::$_1 || false;",
-            "PPHP::$_1;",
-            ast
-        );
+        for sub in self.hoists.iter() {
+            map_sub!(&sub.search, &sub.replace, ast, ctx, impure_operand);
+        }
+        for sub in self.substitutions.iter() {
+            map_sub!(&sub.search, &sub.replace, ast, ctx);
+        }
+    }
+
+    fn root_kinds(&self) -> Option<Vec<php::zend_ast_kind>> {
+        pattern::root_kinds(self.substitutions.iter().chain(self.hoists.iter()).map(|sub| &sub.search))
+    }
+
+    fn describe(&self) -> String {
+        "Simplifies && and || expressions with a constant operand".to_string()
     }
 }
@@ -0,0 +1,57 @@
+use ::std::cell::Cell;
+use ast;
+use context::PhpContext;
+use pattern::{self, Bindings, Pattern, PatternError};
+use php;
+use rules::AstOptimizationRule;
+
+extern "C" {
+    fn ast_fold(zast: ast::ZendAst) -> ast::ZendAst;
+}
+
+const BINARY_OPS: [&str; 22] = [
+    "+", "-", "*", "/", "%", "**", "<<", ">>", "|", "&", "^", ".", "xor",
+    "==", "!=", "===", "!==", "<", "<=", ">", ">=", "<=>",
+];
+
+const UNARY_OPS: [&str; 9] = [
+    "!", "~", "-", "+", "(int)", "(float)", "(string)", "(bool)", "(unset)",
+];
+
+#[derive(Debug)]
+pub struct ConstantFolding {
+    patterns: Vec<Pattern>,
+}
+
+impl ConstantFolding {
+    pub fn new() -> Result<Self, PatternError> {
+        let binary = BINARY_OPS.iter().map(|op| format!("PPHP::$a {} PPHP::$b;", op));
+        let unary = UNARY_OPS.iter().map(|op| format!("{}PPHP::$a;", op));
+        Ok(ConstantFolding {
+            patterns: binary.chain(unary).map(|source| Pattern::compile(&source)).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl AstOptimizationRule for ConstantFolding {
+    fn optimize(&self, ast: &mut ast::ZendAstPtr, ctx: &mut PhpContext) {
+        for pattern in self.patterns.iter() {
+            // the operands are checked and the result computed in one go
+            let folded = Cell::new(::std::ptr::null_mut());
+            map_fun!(pattern, ast, ctx, bindings, |_: &Bindings| {
+                folded.set(unsafe { ast_fold(ast.deref()) });
+                !folded.get().is_null()
+            }, {
+                ast.replace(folded.get());
+            });
+        }
+    }
+
+    fn root_kinds(&self) -> Option<Vec<php::zend_ast_kind>> {
+        pattern::root_kinds(self.patterns.iter())
+    }
+
+    fn describe(&self) -> String {
+        "Evaluates operators and casts on literals and built-in constants".to_string()
+    }
+}
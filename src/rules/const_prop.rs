@@ -0,0 +1,103 @@
+use ::std::os::raw::c_char;
+use ast;
+use context::{self, FunctionKind, PhpContext, SymbolKind};
+use pattern::Value;
+use php;
+use php::_zend_ast_kind::*;
+use rules::AstOptimizationRule;
+
+extern "C" {
+    fn ast_defined_constant(name: *const c_char, name_len: usize, lineno: u32) -> ast::ZendAst;
+}
+
+#[derive(Debug)]
+pub struct ConstantPropagation;
+
+impl ConstantPropagation {
+    pub fn new() -> Self {
+        ConstantPropagation
+    }
+}
+
+impl AstOptimizationRule for ConstantPropagation {
+    fn optimize(&self, ast: &mut ast::ZendAstPtr, ctx: &mut PhpContext) {
+        let node = ast::unwrap_all(ast.deref());
+        let value = match ast::kind_of(node) {
+            ZEND_AST_CONST => constant_value(node, ctx),
+            ZEND_AST_CLASS_CONST => class_constant_value(node, ctx),
+            _ => None,
+        };
+        if let Some(value) = value {
+            if ast::options().debug_trace {
+                trace!("** PPHP rule match **");
+                trace!("==> original AST:");
+                ast::print_node(node, 2);
+                trace!("==> new AST:");
+                ast::print_node(value, 2);
+            }
+            ast.replace(value);
+        }
+    }
+
+    fn root_kinds(&self) -> Option<Vec<php::zend_ast_kind>> {
+        Some(vec![ZEND_AST_CONST as u16, ZEND_AST_CLASS_CONST as u16])
+    }
+
+    fn describe(&self) -> String {
+        "Substitutes the values of constants declared with literals".to_string()
+    }
+}
+
+fn name_of(zast: ast::ZendAst) -> Option<Vec<u8>> {
+    if ast::kind_of(zast) != ZEND_AST_ZVAL {
+        return None;
+    }
+    match Value::from_zval_node(zast) {
+        Some(Value::String(name)) => Some(name),
+        _ => None,
+    }
+}
+
+/**
+ * A literal node holding the value of the constant `zast` refers to, if it
+ * was declared earlier in the file, or by code which has already run.
+ */
+fn constant_value(zast: ast::ZendAst, ctx: &PhpContext) -> Option<ast::ZendAst> {
+    // true, false and null are already literals
+    if Value::from_node(zast).is_some() {
+        return None;
+    }
+    let name_node = ast::get_raw_children(zast)[0];
+    let name = name_of(name_node)?;
+    let lineno = unsafe { (*zast).lineno };
+    let (name, fallback) = ctx.resolve(SymbolKind::Constant, &name, unsafe { (*name_node).attr } as u32);
+    ::std::iter::once(name).chain(fallback).filter_map(|name| match ctx.constant(&name) {
+        Some(value) => Some(value.to_node(lineno)),
+        None => {
+            let key = context::constant_key(&name);
+            let node = unsafe { ast_defined_constant(key.as_ptr() as *const c_char, key.len(), lineno) };
+            if node.is_null() { None } else { Some(node) }
+        }
+    }).next()
+}
+
+/**
+ * A literal node holding the value of the class constant `zast` refers to,
+ * if it belongs to a final class declared earlier in the file.
+ */
+fn class_constant_value(zast: ast::ZendAst, ctx: &PhpContext) -> Option<ast::ZendAst> {
+    let children = ast::get_raw_children(zast);
+    let (class, name) = (name_of(children[0])?, name_of(children[1])?);
+    let class = if context::is_special_class(&class) {
+        // a closure can be bound to another class, and the parent class can't
+        // be final
+        let in_closure = ctx.scope.function.as_ref().map_or(false, |f| f.kind == FunctionKind::Closure);
+        if in_closure || ast::names_equal(&class, b"parent") {
+            return None;
+        }
+        ctx.scope.class.as_ref().and_then(|class| class.name.clone())?
+    } else {
+        ctx.resolve(SymbolKind::Class, &class, unsafe { (*children[0]).attr } as u32).0
+    };
+    ctx.class_constant(&class, &name).map(|value| value.to_node(unsafe { (*zast).lineno }))
+}
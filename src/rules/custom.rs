@@ -1,33 +1,134 @@
 use ast;
+use callback::{self, PhpCallable, Rewrite};
+use constraint;
 use context::PhpContext;
+use pattern::{Binding, Bindings, Pattern, PatternError, Substitution, SubstitutionError};
+use php;
 use rules::AstOptimizationRule;
 
 #[derive(Debug)]
 pub struct CustomSubstitution {
-    from_pattern: String,
-    to_pattern: String,
+    substitution: Substitution,
+    guard: Option<PhpCallable>,
 }
 
 impl CustomSubstitution {
-    pub fn try_create(from: String, to: String) -> Option<Self> {
-        if ast::parse_pattern(&from).is_some() && ast::parse_pattern(&to).is_some() {
-            Some(CustomSubstitution::new(from, to))
-        } else {
-            None
-        }
+    pub fn try_create(from: String, to: String, guard: Option<PhpCallable>) -> Result<Self, SubstitutionError> {
+        Substitution::try_create(&from, &to).map(|substitution| {
+            CustomSubstitution::new(substitution, guard)
+        })
     }
 
-    pub fn new(from: String, to: String) -> Self {
-        CustomSubstitution {from_pattern: from, to_pattern: to}
+    pub fn new(substitution: Substitution, guard: Option<PhpCallable>) -> Self {
+        CustomSubstitution {substitution: substitution, guard: guard}
     }
 }
 
 impl AstOptimizationRule for CustomSubstitution {
     fn optimize(&self, ast: &mut ast::ZendAstPtr, ctx: &mut PhpContext) {
         map_sub!(
-            &self.from_pattern,
-            &self.to_pattern,
-            ast
+            &self.substitution.search,
+            &self.substitution.replace,
+            ast,
+            ctx,
+            |bindings: &Bindings| match self.guard {
+                Some(ref guard) => guard.call_guard(bindings),
+                None => true,
+            }
         );
     }
+
+    fn root_kinds(&self) -> Option<Vec<php::zend_ast_kind>> {
+        self.substitution.search.root_kind().map(|kind| vec![kind])
+    }
+
+    fn describe(&self) -> String {
+        format!("'{}' => '{}'", self.substitution.search.source, self.substitution.replace.source)
+    }
+}
+
+/**
+ * A rule which passes each match to a PHP callback, which returns the
+ * replacement node; see pphp_add_callback_rule.
+ */
+#[derive(Debug)]
+pub struct CustomCallback {
+    pattern: Pattern,
+    callback: PhpCallable,
+}
+
+impl CustomCallback {
+    pub fn try_create(pattern: String, callback: PhpCallable) -> Result<Self, PatternError> {
+        Pattern::compile(&pattern).map(|pattern| CustomCallback::new(pattern, callback))
+    }
+
+    pub fn new(pattern: Pattern, callback: PhpCallable) -> Self {
+        CustomCallback {pattern: pattern, callback: callback}
+    }
+}
+
+impl AstOptimizationRule for CustomCallback {
+    fn optimize(&self, ast: &mut ast::ZendAstPtr, ctx: &mut PhpContext) {
+        map_fun!(&self.pattern, ast, ctx, bindings, {
+            match self.callback.call_rewrite(ast.deref(), &bindings) {
+                Some(Rewrite::Source(src)) => {
+                    let lists: Vec<String> = bindings.iter().filter_map(|(name, binding)| match *binding {
+                        Binding::List(_) => Some(name.clone()),
+                        Binding::Node(_) => None,
+                    }).collect();
+                    match Pattern::compile(&src).and_then(|replace| {
+                        replace.check_bound(bindings.keys())?;
+                        replace.check_lists(&lists, self.pattern.is_statement())?;
+                        Ok(replace)
+                    }) {
+                        Ok(replace) => {
+                            if let Some(replace) = replace.instantiate(&bindings, ast.deref()) {
+                                ast.replace(ast::unwrap_all(replace));
+                            }
+                        }
+                        Err(e) => {
+                            callback::warn(&format!(
+                                "pphp: rewrite callback for '{}' returned an invalid replacement ({}); node left unchanged",
+                                self.pattern.source, e
+                            ));
+                        }
+                    }
+                }
+                Some(Rewrite::Ast(replace)) => {
+                    if known_kinds(replace) {
+                        ast.replace(replace);
+                    } else {
+                        callback::warn(&format!(
+                            "pphp: rewrite callback for '{}' returned an AST with an unknown kind of node; node left unchanged",
+                            self.pattern.source
+                        ));
+                    }
+                }
+                None => {}
+            }
+        });
+    }
+
+    fn root_kinds(&self) -> Option<Vec<php::zend_ast_kind>> {
+        self.pattern.root_kind().map(|kind| vec![kind])
+    }
+
+    fn describe(&self) -> String {
+        format!("'{}' => callback", self.pattern.source)
+    }
+}
+
+/**
+ * Returns true if every node in `zast`, an AST built from a rewrite
+ * callback's array, is one of the kinds the compiler handles.
+ */
+fn known_kinds(zast: ast::ZendAst) -> bool {
+    if zast.is_null() {
+        return true;
+    }
+    let kind = unsafe { (*zast).kind };
+    let known = constraint::AST_KINDS.iter()
+        .any(|&k| k as u16 == kind && k != php::_zend_ast_kind::ZEND_AST_ZNODE);
+    known && (kind == php::_zend_ast_kind::ZEND_AST_ZVAL as u16 ||
+        ast::get_raw_children(zast).into_iter().all(known_kinds))
 }
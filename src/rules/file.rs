@@ -0,0 +1,322 @@
+use ::std::collections::HashSet;
+use ::std::fmt;
+use ::std::fs::File;
+use ::std::io::Read;
+use callback;
+use constraint::{self, Constraint};
+use pattern::{Pattern, PatternError, Substitution};
+use rules::{self, RuleOptions};
+use rules::custom::CustomSubstitution;
+
+/**
+ * A .pphp rule file holds any number of substitution rules, separated by
+ * blank lines. Each rule is a search pattern and its replacement, separated
+ * by a line containing only `=>`, optionally preceded by annotations:
+ *
+ *     # use instanceof for literal class names
+ *     @name instanceof_literal
+ *     @description Replaces is_a() with instanceof
+ *     @priority 10
+ *     @where cls: string
+ *     is_a(PPHP::$obj, PPHP::$cls);
+ *     =>
+ *     PPHP::$obj instanceof PPHP::$cls;
+ *
+ * The annotations are:
+ *
+ * - `@name`: the rule's name; otherwise one is generated.
+ * - `@description`: shown by pphp_list_rules.
+ * - `@priority`: an integer; rules with a higher priority are tried first.
+ *   The default is 0.
+ * - `@enabled`: `true` or `false`; disabled rules can be enabled with
+ *   pphp_enable_rule.
+ * - `@where var: constraint`: constrains PPHP::$var in the search pattern,
+ *   as if it were written PPHP::${'var:constraint'}. Can be repeated.
+ *
+ * Lines starting with `#` are comments.
+ */
+#[derive(Debug)]
+pub struct RuleDef {
+    /// The line the rule starts on.
+    pub line: usize,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub priority: i32,
+    pub enabled: bool,
+    /// Constraints from @where annotations: the line, variable name and
+    /// constraints.
+    pub constraints: Vec<(usize, String, Vec<Constraint>)>,
+    pub search_line: usize,
+    pub search: String,
+    pub replace_line: usize,
+    pub replace: String,
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(line: usize, message: &str) -> Self {
+        ParseError {
+            line: line,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/**
+ * A rule being parsed.
+ */
+struct Partial {
+    def: RuleDef,
+    search: Vec<String>,
+    replace: Option<Vec<String>>,
+}
+
+impl Partial {
+    fn new(line: usize) -> Self {
+        Partial {
+            def: RuleDef {
+                line: line,
+                name: None,
+                description: None,
+                priority: 0,
+                enabled: true,
+                constraints: Vec::new(),
+                search_line: 0,
+                search: String::new(),
+                replace_line: 0,
+                replace: String::new(),
+            },
+            search: Vec::new(),
+            replace: None,
+        }
+    }
+
+    /**
+     * Handle an annotation line, e.g. `@priority 10`.
+     */
+    fn annotate(&mut self, line_number: usize, key: &str, value: &str) -> Result<(), ParseError> {
+        let error = |message: &str| Err(ParseError::new(line_number, message));
+        if value.is_empty() {
+            return error(&format!("@{} needs a value", key));
+        }
+        match key {
+            "name" => {
+                if value.contains(char::is_whitespace) {
+                    return error("rule names can't contain spaces");
+                }
+                self.def.name = Some(value.to_string());
+            }
+            "description" => self.def.description = Some(value.to_string()),
+            "priority" => match value.parse() {
+                Ok(priority) => self.def.priority = priority,
+                Err(_) => return error(&format!("invalid priority '{}'; expected an integer", value)),
+            },
+            "enabled" => match value {
+                "true" => self.def.enabled = true,
+                "false" => self.def.enabled = false,
+                _ => return error(&format!("invalid value '{}' for @enabled; expected true or false", value)),
+            },
+            "where" => {
+                let (var, spec) = match value.find(':') {
+                    Some(i) => (value[..i].trim(), value[i + 1..].trim()),
+                    None => return error("expected @where variable: constraint"),
+                };
+                let var = if var.starts_with("PPHP::$") { &var[7..] } else { var };
+                match constraint::parse_constraints(spec) {
+                    Some(constraints) => self.def.constraints.push((line_number, var.to_string(), constraints)),
+                    None => return error(&format!("unknown constraint '{}'", spec)),
+                }
+            }
+            _ => return error(&format!("unknown annotation @{}", key)),
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, end_line: usize) -> Result<RuleDef, ParseError> {
+        if self.search.is_empty() {
+            return Err(ParseError::new(end_line, "expected a search pattern"));
+        }
+        match self.replace {
+            Some(ref replace) if replace.is_empty() => {
+                Err(ParseError::new(end_line, "expected a replacement after =>"))
+            }
+            Some(replace) => {
+                self.def.search = self.search.join("\n");
+                self.def.replace = replace.join("\n");
+                Ok(self.def)
+            }
+            None => Err(ParseError::new(end_line, "expected => after the search pattern")),
+        }
+    }
+}
+
+/**
+ * If `line` is an annotation such as `@priority 10`, returns the key and
+ * value. Lines like `@unlink($f);` are PHP, not annotations.
+ */
+fn annotation(line: &str) -> Option<(&str, &str)> {
+    if !line.starts_with("@") {
+        return None;
+    }
+    let line = &line[1..];
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+    let key = &line[..end];
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_lowercase()) {
+        Some((key, line[end..].trim()))
+    } else {
+        None
+    }
+}
+
+/**
+ * Parse the contents of a rule file.
+ */
+pub fn parse(source: &str) -> Result<Vec<RuleDef>, ParseError> {
+    let mut rules = Vec::new();
+    let mut names = HashSet::new();
+    let mut current: Option<Partial> = None;
+    let mut line_number = 0;
+    let mut finish = |rule: Partial, line_number: usize, rules: &mut Vec<RuleDef>| -> Result<(), ParseError> {
+        let def = rule.finish(line_number)?;
+        if let Some(ref name) = def.name {
+            if !names.insert(name.clone()) {
+                return Err(ParseError::new(def.line, &format!("duplicate rule name '{}'", name)));
+            }
+        }
+        rules.push(def);
+        Ok(())
+    };
+    for line in source.lines() {
+        line_number += 1;
+        let trimmed = line.trim();
+        if trimmed.starts_with("#") {
+            continue;
+        }
+        if trimmed.is_empty() {
+            if let Some(rule) = current.take() {
+                finish(rule, line_number, &mut rules)?;
+            }
+            continue;
+        }
+        let rule = current.get_or_insert_with(|| Partial::new(line_number));
+        if rule.search.is_empty() {
+            if let Some((key, value)) = annotation(trimmed) {
+                rule.annotate(line_number, key, value)?;
+                continue;
+            }
+        }
+        if trimmed == "=>" {
+            if rule.search.is_empty() {
+                return Err(ParseError::new(line_number, "expected a search pattern before =>"));
+            }
+            if rule.replace.is_some() {
+                return Err(ParseError::new(line_number, "unexpected =>; separate rules with a blank line"));
+            }
+            rule.replace = Some(Vec::new());
+            continue;
+        }
+        match rule.replace {
+            Some(ref mut replace) => {
+                if replace.is_empty() {
+                    rule.def.replace_line = line_number;
+                }
+                replace.push(line.to_string());
+            }
+            None => {
+                if rule.search.is_empty() {
+                    rule.def.search_line = line_number;
+                }
+                rule.search.push(line.to_string());
+            }
+        }
+    }
+    if let Some(rule) = current.take() {
+        finish(rule, line_number + 1, &mut rules)?;
+    }
+    Ok(rules)
+}
+
+/**
+ * Report an error in a pattern starting on `first_line` of the file,
+ * translating the line it's on within the pattern to a line of the file.
+ */
+fn pattern_error(first_line: usize, what: &str, error: PatternError) -> ParseError {
+    let line = first_line + error.line().map_or(0, |line| line.saturating_sub(1) as usize);
+    ParseError::new(line, &format!("{}: {}", what, error.message()))
+}
+
+/**
+ * Compile a parsed rule into a substitution, with the options it should be
+ * registered with.
+ */
+pub fn compile(def: RuleDef) -> Result<(RuleOptions, CustomSubstitution), ParseError> {
+    let mut search = Pattern::compile(&def.search)
+        .map_err(|e| pattern_error(def.search_line, "invalid search pattern", e))?;
+    let replace = Pattern::compile(&def.replace)
+        .map_err(|e| pattern_error(def.replace_line, "invalid replacement pattern", e))?;
+    for &(line, ref var, ref constraints) in def.constraints.iter() {
+        if !search.constrain(var, constraints) {
+            return Err(ParseError::new(line, &format!("PPHP::${} doesn't appear in the search pattern", var)));
+        }
+    }
+    let substitution = Substitution::from_patterns(search, replace)
+        .map_err(|e| pattern_error(def.replace_line, "invalid replacement pattern", e))?;
+    let options = RuleOptions {
+        name: def.name,
+        description: def.description,
+        priority: def.priority,
+        enabled: def.enabled,
+        level: None,
+        safety: None,
+    };
+    Ok((options, CustomSubstitution::new(substitution, None)))
+}
+
+/**
+ * Read a rule file and register its rules, either as persistent rules or
+ * for the current request. Returns the number of rules added. If the file
+ * can't be read or any rule is invalid, none of them are added; a rule whose
+ * name was taken by an earlier rule in the file is left out with a warning.
+ */
+pub fn load(path: &str, persistent: bool) -> Result<usize, String> {
+    if rules::applying() {
+        return Err("rules can't be changed by a guard or rewrite callback".to_string());
+    }
+    let mut source = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut source)).map_err(|e| e.to_string())?;
+    let defs = parse(&source).map_err(|e| e.to_string())?;
+    let mut compiled = Vec::new();
+    for def in defs {
+        if let Some(ref name) = def.name {
+            if rules::rule_exists(name) {
+                return Err(ParseError::new(def.line, &format!("a rule named '{}' already exists", name)).to_string());
+            }
+        }
+        compiled.push(compile(def).map_err(|e| e.to_string())?);
+    }
+    let mut count = 0;
+    for (options, rule) in compiled {
+        let name = options.name.clone();
+        let added = if persistent {
+            rules::add_persistent_rule(options, Box::new(rule))
+        } else {
+            rules::add_rule(options, Box::new(rule))
+        };
+        match added {
+            Some(_) => count += 1,
+            None => callback::warn(&format!("pphp: a rule named '{}' already exists", name.unwrap_or_default())),
+        }
+    }
+    Ok(count)
+}
@@ -1,47 +1,55 @@
 use ast;
 use context::PhpContext;
+use pattern::{self, Substitution, SubstitutionError};
+use php;
 use rules::AstOptimizationRule;
 
 #[derive(Debug)]
-pub struct IncrDecr;
+pub struct IncrDecr {
+    substitutions: Vec<Substitution>,
+}
 
 impl IncrDecr {
-    pub fn new() -> Self {
-        IncrDecr
+    pub fn new() -> Result<Self, SubstitutionError> {
+        let mut substitutions = vec![
+            // replaces postfix with prefix increment/decrement in for loops
+            Substitution::try_create(
+                "for (PPHP::${'...init'}; PPHP::${'...cond'}; PPHP::$iter++) PPHP::$_3;",
+                "for (PPHP::${'...init'}; PPHP::${'...cond'}; ++PPHP::$iter) PPHP::$_3;"
+            )?,
+            Substitution::try_create(
+                "for (PPHP::${'...init'}; PPHP::${'...cond'}; PPHP::$iter--) PPHP::$_3;",
+                "for (PPHP::${'...init'}; PPHP::${'...cond'}; --PPHP::$iter) PPHP::$_3;"
+            )?,
+            // there's a dedicated operator for this, use it!
+            Substitution::try_create("PPHP::$_1 += 1;", "++PPHP::$_1;")?,
+            Substitution::try_create("PPHP::$_1 -= 1;", "--PPHP::$_1;")?,
+        ];
+        // use dedicated in place modification ops
+        for op in vec!["*", "/", "+", "-", "**", "%", "<<", ">>", ".", "|", "&"] {
+            substitutions.push(Substitution::try_create(
+                &format!("PPHP::$_1 = PPHP::$_1 {} PPHP::$_2;", op),
+                &format!("PPHP::$_1 {}= PPHP::$_2;", op)
+            )?);
+        }
+        Ok(IncrDecr {
+            substitutions: substitutions,
+        })
     }
 }
 
 impl AstOptimizationRule for IncrDecr {
     fn optimize(&self, ast: &mut ast::ZendAstPtr, ctx: &mut PhpContext) {
-        // replaces postfix with prefix increment/decrement in for loops
-        map_sub!(
-            "for (PPHP::$_1; PPHP::$_2; PPHP::$iter++) PPHP::$_3;",
-            "for (PPHP::$_1; PPHP::$_2; ++PPHP::$iter) PPHP::$_3;",
-            ast
-        );
-        map_sub!(
-            "for (PPHP::$_1; PPHP::$_2; PPHP::$iter--) PPHP::$_3;",
-            "for (PPHP::$_1; PPHP::$_2; --PPHP::$iter) PPHP::$_3;",
-            ast
-        );
-        // there's a dedicated operator for this, use it!
-        map_sub!(
-            "PPHP::$_1 += 1;",
-            "++PPHP::$_1;",
-            ast
-        );
-        map_sub!(
-            "PPHP::$_1 -= 1;",
-            "--PPHP::$_1;",
-            ast
-        );
-        // use dedicated in place modification ops
-        for op in vec!["*", "/", "+", "-", "**", "%", "<<", ">>", ".", "|", "&"] {
-            map_sub!(
-                &format!("PPHP::$_1 = PPHP::$_1 {} PPHP::$_2;", op),
-                &format!("PPHP::$_1 {}= PPHP::$_2;", op),
-                ast
-            );
+        for sub in self.substitutions.iter() {
+            map_sub!(&sub.search, &sub.replace, ast, ctx);
         }
     }
+
+    fn root_kinds(&self) -> Option<Vec<php::zend_ast_kind>> {
+        pattern::root_kinds(self.substitutions.iter().map(|sub| &sub.search))
+    }
+
+    fn describe(&self) -> String {
+        "Uses prefix increment/decrement and compound assignment operators".to_string()
+    }
 }
@@ -0,0 +1,327 @@
+use ::std::collections::HashMap;
+use ast;
+use context::{self, DeclaredFunction, PhpContext, SymbolKind};
+use pattern::{self, Value};
+use php;
+use php::_zend_ast_kind::*;
+use purity;
+use rules::AstOptimizationRule;
+
+extern "C" {
+    fn function_arg_may_be_ref(name: ast::ZendAst, arg: u32) -> php::zend_bool;
+}
+
+const ZEND_ACC_RETURN_REFERENCE: u32 = 0x4000000;
+const ZEND_PARAM_REF: u32 = 1;
+const ZEND_PARAM_VARIADIC: u32 = 2;
+
+/**
+ * Built-in functions which look at or change the scope they're called from,
+ * so they can't be moved into another function.
+ */
+const SCOPE_FUNCTIONS: [&str; 17] = [
+    "assert", "call_user_func", "call_user_func_array", "compact", "debug_backtrace",
+    "debug_print_backtrace", "extract", "forward_static_call", "forward_static_call_array",
+    "func_get_arg", "func_get_args", "func_num_args", "get_called_class", "get_class",
+    "get_defined_vars", "get_parent_class", "parse_str",
+];
+
+#[derive(Debug)]
+pub struct FunctionInlining;
+
+impl FunctionInlining {
+    pub fn new() -> Self {
+        FunctionInlining
+    }
+}
+
+impl AstOptimizationRule for FunctionInlining {
+    /**
+     * Calls are inlined from their parent, which tells whether the call is
+     * somewhere an expression can't replace it, such as a by-reference
+     * argument or the container of an assignment `f()[0] = 1`.
+     */
+    fn optimize(&self, ast: &mut ast::ZendAstPtr, ctx: &mut PhpContext) {
+        let parent = ast.deref();
+        let children = match ast::kind_of(parent) {
+            // arguments are inlined from the call, which knows the function
+            ZEND_AST_ARG_LIST => return,
+            ZEND_AST_CALL => {
+                let children = ast::get_raw_children(parent);
+                ast::get_indexed_children(children[1]).into_iter()
+                    .filter(|&(i, _)| unsafe { function_arg_may_be_ref(children[0], i as u32) } == 0)
+                    .collect()
+            }
+            _ => ast::get_indexed_children(parent).into_iter()
+                .filter(|&(i, _)| !is_write_position(parent, i))
+                .collect::<Vec<_>>(),
+        };
+        for (_, mut child) in children {
+            let call = child.deref();
+            if ast::kind_of(call) != ZEND_AST_CALL {
+                continue;
+            }
+            if let Some(inlined) = inline(call, ctx) {
+                if ast::options().debug_trace {
+                    trace!("** PPHP rule match **");
+                    trace!("==> original AST:");
+                    ast::print_node(call, 2);
+                    trace!("==> new AST:");
+                    ast::print_node(inlined, 2);
+                }
+                child.replace(inlined);
+                ast.set_dirty(true);
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        "Inlines calls to functions which only return an expression".to_string()
+    }
+}
+
+/**
+ * Returns true if the child of `parent` at `index` may be written to, or
+ * taken by reference.
+ */
+fn is_write_position(parent: ast::ZendAst, index: usize) -> bool {
+    match (ast::kind_of(parent), index) {
+        (ZEND_AST_DIM, 0) | (ZEND_AST_PROP, 0) | (ZEND_AST_ASSIGN_REF, 1) => true,
+        (ZEND_AST_ARRAY_ELEM, 0) => unsafe { (*parent).attr != 0 },
+        _ => false,
+    }
+}
+
+fn name_of(zast: ast::ZendAst) -> Option<Vec<u8>> {
+    if zast.is_null() || ast::kind_of(zast) != ZEND_AST_ZVAL {
+        return None;
+    }
+    match Value::from_zval_node(zast) {
+        Some(Value::String(name)) => Some(name),
+        _ => None,
+    }
+}
+
+/**
+ * A function which can be inlined into a particular call: the call is
+ * replaced by a copy of `expr`, with each parameter replaced by its argument
+ * or default value.
+ */
+struct Inlining {
+    /// The names of the parameters and their default values.
+    params: Vec<(Vec<u8>, Option<Value>)>,
+    /// How many times each parameter is used in `expr`.
+    uses: HashMap<Vec<u8>, usize>,
+    expr: ast::ZendAst,
+    /// The name nodes in `expr` which would resolve differently where the
+    /// call is, and the fully qualified names they're replaced with.
+    names: Vec<(ast::ZendAst, Vec<u8>)>,
+    /// Whether evaluating `expr` may run code which could change the
+    /// caller's variables, such as a function or magic method.
+    runs_code: bool,
+}
+
+impl Inlining {
+    /**
+     * Check that `function` has no more than a `return` statement, and that
+     * its expression can be evaluated where `caller` is.
+     */
+    fn plan(function: &DeclaredFunction, caller: &PhpContext) -> Option<Self> {
+        let flags = unsafe { (*(function.decl as ast::ZendAstDecl)).flags };
+        // params, uses, statements and return type
+        let children = ast::get_raw_children(function.decl);
+        if flags & ZEND_ACC_RETURN_REFERENCE != 0 || !children[3].is_null() {
+            return None;
+        }
+        let mut params = Vec::new();
+        if !children[0].is_null() {
+            for param in ast::get_raw_children(children[0]) {
+                let attr = unsafe { (*param).attr } as u32;
+                // type, name and default
+                let parts = ast::get_raw_children(param);
+                if attr & (ZEND_PARAM_REF | ZEND_PARAM_VARIADIC) != 0 || !parts[0].is_null() {
+                    return None;
+                }
+                let default = if parts[2].is_null() { None } else { Some(Value::from_node(parts[2])?) };
+                params.push((name_of(parts[1])?, default));
+            }
+        }
+        let statements = ast::get_raw_children(children[2]);
+        if statements.len() != 1 || ast::kind_of(statements[0]) != ZEND_AST_RETURN {
+            return None;
+        }
+        let expr = ast::get_raw_children(statements[0])[0];
+        // the call would become something which can be written to
+        match ast::kind_of(expr) {
+            ZEND_AST_VAR | ZEND_AST_DIM | ZEND_AST_PROP | ZEND_AST_STATIC_PROP => return None,
+            _ => {}
+        }
+        let mut inlining = Inlining {
+            uses: params.iter().map(|&(ref name, _)| (name.clone(), 0)).collect(),
+            params: params,
+            expr: expr,
+            names: Vec::new(),
+            runs_code: false,
+        };
+        let callee = PhpContext {
+            namespace: function.namespace.clone(),
+            imports: function.imports.clone(),
+            ..PhpContext::new()
+        };
+        if inlining.check(expr, &callee, caller) { Some(inlining) } else { None }
+    }
+
+    /**
+     * Returns true if `zast` can be moved from `callee` to `caller`: it only
+     * reads its parameters, and doesn't do anything which depends on the
+     * function it's in.
+     */
+    fn check(&mut self, zast: ast::ZendAst, callee: &PhpContext, caller: &PhpContext) -> bool {
+        if zast.is_null() || Value::from_node(zast).is_some() {
+            return true;
+        }
+        let kind = ast::kind_of(zast);
+        let children = ast::get_raw_children(zast);
+        match kind {
+            ZEND_AST_VAR => {
+                // also rules out $this, and variable variables
+                return match name_of(children[0]).and_then(|name| self.uses.get_mut(&name)) {
+                    Some(uses) => {
+                        *uses += 1;
+                        true
+                    }
+                    None => false,
+                };
+            }
+            ZEND_AST_ARRAY_ELEM if unsafe { (*zast).attr } != 0 => return false,
+            ZEND_AST_CALL | ZEND_AST_METHOD_CALL | ZEND_AST_STATIC_CALL | ZEND_AST_NEW => {
+                if !check_args(zast) {
+                    return false;
+                }
+                self.runs_code = true;
+            }
+            ZEND_AST_DIM | ZEND_AST_PROP | ZEND_AST_STATIC_PROP | ZEND_AST_CLASS_CONST | ZEND_AST_CLONE => {
+                self.runs_code = true;
+            }
+            ZEND_AST_CONST | ZEND_AST_UNARY_PLUS | ZEND_AST_UNARY_MINUS | ZEND_AST_CAST | ZEND_AST_SILENCE |
+            ZEND_AST_UNARY_OP | ZEND_AST_BINARY_OP | ZEND_AST_GREATER | ZEND_AST_GREATER_EQUAL |
+            ZEND_AST_AND | ZEND_AST_OR | ZEND_AST_COALESCE | ZEND_AST_CONDITIONAL | ZEND_AST_ARRAY |
+            ZEND_AST_ARRAY_ELEM | ZEND_AST_ENCAPS_LIST | ZEND_AST_INSTANCEOF | ZEND_AST_ARG_LIST => {}
+            _ => return false,
+        }
+        let name_child = pattern::name_child(kind);
+        if let Some((index, symbol)) = name_child {
+            let node = children[index];
+            let name = match name_of(node) {
+                Some(ref name) if symbol != SymbolKind::Class || !context::is_special_class(name) => name.clone(),
+                _ => return false,
+            };
+            let attr = unsafe { (*node).attr } as u32;
+            let (primary, fallback) = callee.resolve(symbol, &name, attr);
+            if symbol == SymbolKind::Function {
+                // a function declared in the file could be inlined in turn,
+                // and never stop if it's recursive
+                let scoped = ::std::iter::once(&primary).chain(fallback.iter()).any(|name| {
+                    caller.function(name).is_some() ||
+                        SCOPE_FUNCTIONS.iter().any(|f| ast::names_equal(f.as_bytes(), name))
+                });
+                if scoped {
+                    return false;
+                }
+            }
+            if caller.resolve(symbol, &name, attr) != (primary.clone(), fallback.clone()) {
+                // a name with a fallback depends on what's declared when it's
+                // run, so can only be left as it is
+                if fallback.is_some() {
+                    return false;
+                }
+                self.names.push((node, primary));
+            }
+        }
+        children.iter().enumerate()
+            .filter(|&(i, _)| name_child.map_or(true, |(index, _)| i != index))
+            .all(|(_, &child)| self.check(child, callee, caller))
+    }
+
+    /**
+     * Build the expression which replaces `call`, or None if its arguments
+     * can't be substituted for the parameters: each argument must be
+     * evaluated once, and have the same value wherever it's used.
+     */
+    fn inline(&self, call: ast::ZendAst) -> Option<ast::ZendAst> {
+        let args = ast::get_raw_children(ast::get_raw_children(call)[1]);
+        if args.len() > self.params.len() {
+            return None;
+        }
+        for (i, &(ref name, ref default)) in self.params.iter().enumerate() {
+            match args.get(i) {
+                Some(&arg) => {
+                    if ast::kind_of(arg) == ZEND_AST_UNPACK || !purity::is_pure(arg) {
+                        return None;
+                    }
+                    // a variable might have changed by the time it's used
+                    let stable = match ast::kind_of(arg) {
+                        ZEND_AST_ZVAL | ZEND_AST_CONST => true,
+                        ZEND_AST_VAR => !self.runs_code,
+                        _ => false,
+                    };
+                    if !stable && (self.runs_code || self.uses[name] > 1) {
+                        return None;
+                    }
+                }
+                None if default.is_none() => return None,
+                None => {}
+            }
+        }
+        let lineno = unsafe { (*call).lineno };
+        let values: HashMap<&[u8], ast::ZendAst> = self.params.iter().enumerate().map(|(i, &(ref name, ref default))| {
+            let value = match args.get(i) {
+                Some(&arg) => arg,
+                None => default.as_ref().unwrap().to_node(lineno),
+            };
+            (&name[..], value)
+        }).collect();
+        Some(ast::copy_tree(self.expr, &mut |node| {
+            if let Some(&(_, ref name)) = self.names.iter().find(|&&(n, _)| n == node) {
+                return Some(Value::String(name.clone()).to_zval_node(context::ZEND_NAME_FQ as php::zend_ast_attr, lineno));
+            }
+            if ast::kind_of(node) != ZEND_AST_VAR {
+                return None;
+            }
+            name_of(ast::get_raw_children(node)[0])
+                .and_then(|name| values.get(&name[..]).map(|&value| ast::copy_tree(value, &mut |_| None)))
+        }))
+    }
+}
+
+/**
+ * Returns true if the arguments of a call in the inlined expression can't
+ * be unpacked, or taken by reference: a parameter passed by reference would
+ * become the caller's variable.
+ */
+fn check_args(call: ast::ZendAst) -> bool {
+    let kind = ast::kind_of(call);
+    let children = ast::get_raw_children(call);
+    let args = if kind == ZEND_AST_METHOD_CALL || kind == ZEND_AST_STATIC_CALL { children[2] } else { children[1] };
+    ast::get_raw_children(args).into_iter().enumerate().all(|(i, arg)| match ast::kind_of(arg) {
+        ZEND_AST_UNPACK => false,
+        ZEND_AST_VAR | ZEND_AST_DIM | ZEND_AST_PROP | ZEND_AST_STATIC_PROP => {
+            kind == ZEND_AST_CALL && unsafe { function_arg_may_be_ref(children[0], i as u32) } == 0
+        }
+        _ => true,
+    })
+}
+
+/**
+ * The expression which replaces `call`, if it calls a function declared
+ * earlier in the file which can be inlined.
+ */
+fn inline(call: ast::ZendAst, ctx: &PhpContext) -> Option<ast::ZendAst> {
+    let name_node = ast::get_raw_children(call)[0];
+    let name = name_of(name_node)?;
+    // an unqualified call in a namespace only calls the global function if
+    // the namespaced one isn't declared, so only the latter is looked up
+    let (name, _) = ctx.resolve(SymbolKind::Function, &name, unsafe { (*name_node).attr } as u32);
+    let function = ctx.function(&name)?;
+    Inlining::plan(function, ctx)?.inline(call)
+}
@@ -1,24 +1,86 @@
 use ast;
-use context::PhpContext;
+use context::{self, PhpContext, SymbolKind};
+use pattern::{Binding, Bindings, Substitution, SubstitutionError, Value};
+use php;
 use rules::AstOptimizationRule;
 
 #[derive(Debug)]
-pub struct InstanceOf;
+pub struct InstanceOf {
+    substitution: Substitution,
+}
 
 impl InstanceOf {
-    pub fn new() -> Self {
-        InstanceOf
+    pub fn new() -> Result<Self, SubstitutionError> {
+        // replaces is_a calls with instanceof constructs; the class name has
+        // to be a string literal, since instanceof can't take an arbitrary
+        // expression. It's replaced by a class name node before the
+        // replacement is instantiated
+        Ok(InstanceOf {
+            substitution: Substitution::try_create(
+                "\\is_a(PPHP::$_1, PPHP::${'_2:string'});",
+                "PPHP::$_1 instanceof PPHP::$_2;"
+            )?,
+        })
     }
 }
 
 impl AstOptimizationRule for InstanceOf {
     fn optimize(&self, ast: &mut ast::ZendAstPtr, ctx: &mut PhpContext) {
-        // replaces is_a calls with instanceof constructs
-        // TODO: constrain to string constant
-        map_sub!(
-            "is_a(PPHP::$_1, PPHP::$_2);",
-            "PPHP::$_1 instanceof PPHP::$_2;",
-            ast
-        );
+        let search = &self.substitution.search;
+        let guard = |bindings: &Bindings| class_name(bindings).is_some() && calls_global(ast.deref(), ctx);
+        map_fun!(search, ast, ctx, bindings, guard, {
+            if let Some(class) = class_name(&bindings) {
+                let lineno = unsafe { (*ast.deref()).lineno };
+                let class = Value::String(class).to_zval_node(context::ZEND_NAME_FQ as php::zend_ast_attr, lineno);
+                bindings.insert("_2".to_string(), Binding::Node(class));
+                if let Some(replace) = self.substitution.replace.instantiate(&bindings, ast.deref()) {
+                    ast.replace(ast::unwrap_all(replace));
+                }
+            }
+        });
+    }
+
+    fn root_kinds(&self) -> Option<Vec<php::zend_ast_kind>> {
+        self.substitution.search.root_kind().map(|kind| vec![kind])
+    }
+
+    fn describe(&self) -> String {
+        "Replaces is_a() with a string class name by instanceof".to_string()
+    }
+}
+
+/**
+ * The fully qualified class name for the string bound to `_2`. is_a()
+ * accepts a leading backslash, but looks up self, parent and static as
+ * classes of those names, which instanceof would resolve relative to the
+ * current class.
+ */
+fn class_name(bindings: &Bindings) -> Option<Vec<u8>> {
+    let name = match bindings.get("_2") {
+        Some(&Binding::Node(node)) => match Value::from_zval_node(node) {
+            Some(Value::String(name)) => name,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let name = if name.starts_with(b"\\") { &name[1..] } else { &name[..] };
+    if name.is_empty() || context::is_special_class(name) {
+        return None;
+    }
+    Some(name.to_vec())
+}
+
+/**
+ * Returns true if the call `zast` is certainly to the global is_a(): an
+ * unqualified call in a namespace falls back to it only if the namespace
+ * doesn't declare its own.
+ */
+fn calls_global(zast: ast::ZendAst, ctx: &PhpContext) -> bool {
+    let name = ast::get_raw_children(ast::unwrap_all(zast))[0];
+    match Value::from_zval_node(name) {
+        Some(Value::String(name_str)) => {
+            ctx.resolve(SymbolKind::Function, &name_str, unsafe { (*name).attr } as u32).1.is_none()
+        }
+        _ => false,
     }
 }
@@ -1,18 +1,346 @@
+use ::std::cell::Cell;
 use ast;
 use context::PhpContext;
+use pattern::{Binding, Bindings, Pattern, PatternError, Value};
+use php;
+use php::_zend_ast_kind::*;
 use rules::AstOptimizationRule;
 
+extern "C" {
+    fn function_arg_may_be_ref(name: ast::ZendAst, arg: u32) -> php::zend_bool;
+}
+
+/**
+ * A form of `for` loop which counts `$i` from one integer literal towards
+ * another by `step`, including the last value if `inclusive`.
+ */
+#[derive(Debug)]
+struct LoopForm {
+    pattern: Pattern,
+    step: php::zend_long,
+    inclusive: bool,
+}
+
+/**
+ * A loop which can be unrolled: `body` is run `count` times, with the
+ * variable `var` set to `first`, `first + step`, and so on.
+ */
+struct Unrolling {
+    var: ast::ZendAst,
+    name: Vec<u8>,
+    first: php::zend_long,
+    step: php::zend_long,
+    count: php::zend_long,
+    body: ast::ZendAst,
+}
+
 #[derive(Debug)]
-pub struct LoopUnroll;
+pub struct LoopUnroll {
+    forms: Vec<LoopForm>,
+}
 
 impl LoopUnroll {
-    pub fn new() -> Self {
-        LoopUnroll
+    pub fn new() -> Result<Self, PatternError> {
+        let mut forms = Vec::new();
+        for &(comparison, step, inclusive) in [("<", 1, false), ("<=", 1, true), (">", -1, false), (">=", -1, true)].iter() {
+            let op = if step > 0 { "++" } else { "--" };
+            for update in [format!("PPHP::$i{}", op), format!("{}PPHP::$i", op)].iter() {
+                let source = format!(
+                    "for (PPHP::${{'i:var'}} = PPHP::${{'a:int'}}; PPHP::$i {} PPHP::${{'b:int'}}; {}) PPHP::$body;",
+                    comparison, update
+                );
+                forms.push(LoopForm {
+                    pattern: Pattern::compile(&source)?,
+                    step: step,
+                    inclusive: inclusive,
+                });
+            }
+        }
+        Ok(LoopUnroll {
+            forms: forms,
+        })
+    }
+
+    /**
+     * Decide whether a loop matching `form` can be unrolled.
+     */
+    fn plan(&self, form: &LoopForm, bindings: &Bindings) -> Option<Unrolling> {
+        let node = |name: &str| match bindings.get(name) {
+            Some(&Binding::Node(node)) => Some(node),
+            _ => None,
+        };
+        let long = |name: &str| match node(name).and_then(Value::from_zval_node) {
+            Some(Value::Long(l)) => Some(l),
+            _ => None,
+        };
+        let (var, body) = (node("i")?, node("body")?);
+        let (first, last) = (long("a")?, long("b")?);
+        let name = var_name(var)?;
+
+        let distance = if form.step > 0 { last.checked_sub(first)? } else { first.checked_sub(last)? };
+        let count = (if form.inclusive { distance.checked_add(1)? } else { distance }).max(0);
+        // loops with as many iterations as the limit are left alone
+        if count >= ast::options().unroll_limit as php::zend_long {
+            return None;
+        }
+        // the value after the loop must be representable too
+        first.checked_add(form.step * count)?;
+
+        if !can_unroll(body, &name, 0) {
+            return None;
+        }
+        Some(Unrolling {
+            var: var,
+            name: name,
+            first: first,
+            step: form.step,
+            count: count,
+            body: body,
+        })
     }
 }
 
 impl AstOptimizationRule for LoopUnroll {
     fn optimize(&self, ast: &mut ast::ZendAstPtr, ctx: &mut PhpContext) {
-        // TODO
+        for form in self.forms.iter() {
+            // the loop is planned once, by the guard
+            let planned = Cell::new(None);
+            map_fun!(&form.pattern, ast, ctx, bindings, |bindings: &Bindings| {
+                let unrolling = self.plan(form, bindings);
+                let found = unrolling.is_some();
+                planned.set(unrolling);
+                found
+            }, {
+                if let Some(unrolling) = planned.take() {
+                    let unrolled = unroll(&unrolling, ast.deref());
+                    ast.replace(unrolled);
+                }
+            });
+        }
+    }
+
+    fn root_kinds(&self) -> Option<Vec<php::zend_ast_kind>> {
+        Some(vec![ZEND_AST_FOR as u16])
+    }
+
+    fn describe(&self) -> String {
+        "Unrolls loops with a small constant number of iterations".to_string()
+    }
+}
+
+/**
+ * Build the statements which replace the loop `original`: a copy of the body
+ * for each iteration, preceded by an assignment of that iteration's value,
+ * and a final assignment of the value the variable has after the loop.
+ */
+fn unroll(unrolling: &Unrolling, original: ast::ZendAst) -> ast::ZendAst {
+    let lineno = unsafe { (*original).lineno };
+    let assign = |value: php::zend_long| unsafe {
+        let var = ast::copy_tree(unrolling.var, &mut |_| None);
+        let value = Value::Long(value).to_zval_node(0, lineno);
+        let node = php::zend_ast_create_ex(ZEND_AST_ASSIGN as u16, 0, var, value);
+        (*node).lineno = lineno;
+        node
+    };
+    let mut statements = Vec::new();
+    for i in 0 .. unrolling.count {
+        let value = unrolling.first + unrolling.step * i;
+        statements.push(assign(value));
+        statements.push(ast::copy_tree(unrolling.body, &mut |node| substitute(node, &unrolling.name, value)));
+    }
+    statements.push(assign(unrolling.first + unrolling.step * unrolling.count));
+    let list = ast::create_list(ZEND_AST_STMT_LIST as u16, 0, &statements);
+    unsafe { (*list).lineno = lineno };
+    list
+}
+
+/**
+ * The name of a variable node, or None if it isn't a simple variable (e.g.
+ * `$$name`.)
+ */
+fn var_name(zast: ast::ZendAst) -> Option<Vec<u8>> {
+    if zast.is_null() || ast::kind_of(zast) != ZEND_AST_VAR {
+        return None;
+    }
+    match ast::get_raw_children(zast)[0] {
+        child if ast::kind_of(child) == ZEND_AST_ZVAL => match Value::from_zval_node(child) {
+            Some(Value::String(name)) => Some(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_var(zast: ast::ZendAst, name: &[u8]) -> bool {
+    var_name(zast).map_or(false, |n| n == name)
+}
+
+/**
+ * Whether `zast`, the target of an assignment, assigns to the variable
+ * `name`, including through list() or a reference.
+ */
+fn writes_var(zast: ast::ZendAst, name: &[u8]) -> bool {
+    if zast.is_null() {
+        return false;
+    }
+    match ast::kind_of(zast) {
+        ZEND_AST_ARRAY | ZEND_AST_LIST | ZEND_AST_ARRAY_ELEM | ZEND_AST_REF => {
+            ast::get_raw_children(zast).into_iter().any(|child| writes_var(child, name))
+        }
+        _ => is_var(zast, name),
+    }
+}
+
+/**
+ * Whether copies of the loop body `zast` can be run in place of the loop
+ * with the variable `name` as its counter. `nesting` is the number of loops
+ * and switches inside the body which enclose `zast`, which a break or
+ * continue can leave without leaving the body.
+ */
+fn can_unroll(zast: ast::ZendAst, name: &[u8], nesting: php::zend_long) -> bool {
+    if zast.is_null() {
+        return true;
+    }
+    let children = ast::get_raw_children(zast);
+    let kind = ast::kind_of(zast);
+    let nesting = match kind {
+        // declarations and labels can't be repeated, static variables would
+        // be separate in each copy, and these could change $i without
+        // assigning it by name
+        ZEND_AST_FUNC_DECL | ZEND_AST_CLASS | ZEND_AST_LABEL | ZEND_AST_GOTO |
+        ZEND_AST_STATIC | ZEND_AST_INCLUDE_OR_EVAL => return false,
+        ZEND_AST_BREAK | ZEND_AST_CONTINUE => {
+            let depth = match children[0] {
+                child if child.is_null() => 1,
+                child => match Value::from_zval_node(child) {
+                    Some(Value::Long(depth)) => depth,
+                    _ => return false,
+                },
+            };
+            return depth <= nesting;
+        }
+        ZEND_AST_VAR => {
+            return match var_name(zast) {
+                Some(var) => var != b"GLOBALS",
+                None => false,
+            };
+        }
+        ZEND_AST_CLOSURE => {
+            // the body has its own scope, but `use ($i)` captures it
+            let uses = children[1];
+            return uses.is_null() || ast::get_raw_children(uses).into_iter().all(|var| {
+                Value::from_zval_node(var) != Some(Value::String(name.to_vec()))
+            });
+        }
+        ZEND_AST_ASSIGN | ZEND_AST_ASSIGN_OP | ZEND_AST_PRE_INC | ZEND_AST_PRE_DEC |
+        ZEND_AST_POST_INC | ZEND_AST_POST_DEC | ZEND_AST_UNSET | ZEND_AST_GLOBAL => {
+            if writes_var(children[0], name) {
+                return false;
+            }
+            nesting
+        }
+        ZEND_AST_ASSIGN_REF => {
+            if writes_var(children[0], name) || writes_var(children[1], name) {
+                return false;
+            }
+            nesting
+        }
+        ZEND_AST_ARRAY_ELEM if unsafe { (*zast).attr } != 0 => {
+            // [&$i]
+            if writes_var(children[0], name) {
+                return false;
+            }
+            nesting
+        }
+        ZEND_AST_CATCH => {
+            if Value::from_zval_node(children[1]) == Some(Value::String(name.to_vec())) {
+                return false;
+            }
+            nesting
+        }
+        ZEND_AST_CALL => {
+            let function = children[0];
+            let function_name = if ast::kind_of(function) == ZEND_AST_ZVAL {
+                Value::from_zval_node(function)
+            } else {
+                None
+            };
+            if let Some(Value::String(function_name)) = function_name {
+                let function_name = function_name.rsplit(|&c| c == b'\\').next().unwrap_or(&[]);
+                if ["extract", "parse_str", "mb_parse_str"].iter().any(|f| ast::names_equal(f.as_bytes(), function_name)) {
+                    return false;
+                }
+            }
+            let args = ast::get_raw_children(children[1]);
+            for (i, arg) in args.iter().enumerate() {
+                if is_var(*arg, name) && unsafe { function_arg_may_be_ref(function, i as u32) } != 0 {
+                    return false;
+                }
+            }
+            return can_unroll(function, name, nesting) && args.into_iter().all(|arg| can_unroll(arg, name, nesting));
+        }
+        ZEND_AST_ARG_LIST => {
+            // arguments to methods and constructors might be references
+            if children.iter().any(|&arg| is_var(arg, name)) {
+                return false;
+            }
+            nesting
+        }
+        ZEND_AST_FOREACH => {
+            if writes_var(children[1], name) || writes_var(children[2], name) {
+                return false;
+            }
+            nesting + 1
+        }
+        ZEND_AST_FOR | ZEND_AST_WHILE | ZEND_AST_DO_WHILE | ZEND_AST_SWITCH => nesting + 1,
+        _ => nesting,
+    };
+    children.into_iter().all(|child| can_unroll(child, name, nesting))
+}
+
+/**
+ * Whether a variable in the `index`th child of a `kind` node must be left as
+ * a variable, rather than being replaced with its value.
+ */
+fn keeps_variable(kind: php::_zend_ast_kind, index: usize) -> bool {
+    match kind {
+        // written to, passed or returned by reference, or used as a name
+        ZEND_AST_ARG_LIST | ZEND_AST_ISSET | ZEND_AST_EMPTY | ZEND_AST_UNSET |
+        ZEND_AST_RETURN | ZEND_AST_YIELD | ZEND_AST_REF => true,
+        ZEND_AST_ASSIGN | ZEND_AST_ASSIGN_REF | ZEND_AST_ASSIGN_OP | ZEND_AST_PRE_INC |
+        ZEND_AST_PRE_DEC | ZEND_AST_POST_INC | ZEND_AST_POST_DEC | ZEND_AST_DIM |
+        ZEND_AST_PROP | ZEND_AST_STATIC_PROP | ZEND_AST_CALL | ZEND_AST_CLASS_CONST |
+        ZEND_AST_NEW | ZEND_AST_FOREACH => index == 0,
+        // `1 instanceof Foo` doesn't compile before PHP 7.3
+        ZEND_AST_METHOD_CALL | ZEND_AST_STATIC_CALL | ZEND_AST_INSTANCEOF => index <= 1,
+        _ => false,
+    }
+}
+
+/**
+ * Used with ast::copy_tree to replace reads of the variable `name` with
+ * `value`. The variable is still assigned before each copy of the body, so
+ * any use which needs the variable itself is left alone.
+ */
+fn substitute(zast: ast::ZendAst, name: &[u8], value: php::zend_long) -> Option<ast::ZendAst> {
+    if is_var(zast, name) {
+        return Some(Value::Long(value).to_zval_node(0, unsafe { (*zast).lineno }));
+    }
+    let kind = ast::kind_of(zast);
+    if kind == ZEND_AST_CLOSURE {
+        // a closure's $i is a different variable
+        return Some(ast::copy_tree(zast, &mut |_| None));
+    }
+    let children = ast::get_raw_children(zast);
+    if !children.iter().enumerate().any(|(i, &child)| keeps_variable(kind, i) && is_var(child, name)) {
+        return None;
     }
+    let children: Vec<ast::ZendAst> = children.into_iter().enumerate().map(|(i, child)| {
+        if keeps_variable(kind, i) && is_var(child, name) {
+            ast::copy_tree(child, &mut |_| None)
+        } else {
+            ast::copy_tree(child, &mut |node| substitute(node, name, value))
+        }
+    }).collect();
+    Some(ast::copy_node(zast, &children))
 }
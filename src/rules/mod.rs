@@ -1,61 +1,729 @@
+use ::std::cell::{Cell, RefCell};
 use ::std::collections::HashMap;
-use ::std::fmt::Debug;
+use ::std::fmt::{Debug, Display};
 use ::std::sync::Arc;
-use ::std::sync::Mutex;
-use ast::{ZendAst, ZendAstPtr};
-use context::PhpContext;
+use ::std::sync::{Mutex, MutexGuard};
+use ast::{self, hash_tree, unwrap_all, ZendAst, ZendAstPtr};
+use callback;
+use context::{self, PhpContext};
+use php;
 
 pub mod custom;
+pub mod file;
 
 mod branch_elim;
 mod cond_elim;
+mod const_fold;
+mod const_prop;
 mod incr_decr;
+mod inline;
 mod instanceof;
 mod loop_unroll;
 
-pub trait AstOptimizationRule: Send + Debug {
+/**
+ * A rule's optimize may run on several threads at once, since persistent
+ * rules are shared by every thread.
+ */
+pub trait AstOptimizationRule: Send + Sync + Debug {
     /**
      * This function should process the provided AST node, modifying it in
      * place if it's a match.
      */
     fn optimize(&self, ast: &mut ZendAstPtr, context: &mut PhpContext);
+
+    /**
+     * The kinds of node this rule can modify, used to skip calling optimize
+     * on nodes that can't match. Nodes are dispatched by their kind after
+     * unwrapping single-statement lists, as patterns are matched. None means
+     * the rule needs to see every node.
+     */
+    fn root_kinds(&self) -> Option<Vec<php::zend_ast_kind>> {
+        None
+    }
+
+    /**
+     * A short description of what this rule does, for pphp_list_rules.
+     */
+    fn describe(&self) -> String;
 }
 
-pub type RulesVec = Vec<Box<AstOptimizationRule>>;
+/**
+ * Whether a rule can change what a program does.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Safety {
+    /// The rewritten code always behaves exactly like the original.
+    Safe,
+    /// The rewritten code can behave differently in edge cases, e.g. when
+    /// an operand isn't the type the rule expects.
+    Unsafe,
+}
+
+impl Safety {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Safety::Safe => "safe",
+            Safety::Unsafe => "unsafe",
+        }
+    }
+}
+
+// The optimization levels, selected by pphp.optimization_level. Each level
+// includes the rules of the levels below it.
+
+/// Only custom rules.
+pub const LEVEL_NONE: u8 = 0;
+/// Simplifications which never change behavior, like removing dead branches.
+pub const LEVEL_SAFE: u8 = 1;
+/// Rewrites which are correct for typical code; the default.
+pub const LEVEL_STANDARD: u8 = 2;
+/// Transforms which trade code size for speed, like loop unrolling.
+pub const LEVEL_AGGRESSIVE: u8 = 3;
+
+/**
+ * How a rule should be registered.
+ */
+#[derive(Clone, Debug)]
+pub struct RuleOptions {
+    /// A name for the rule; one is generated if this is None.
+    pub name: Option<String>,
+    /// Overrides the rule's own description.
+    pub description: Option<String>,
+    /// Rules with a higher priority are tried first; rules with the same
+    /// priority are tried in the order they were added.
+    pub priority: i32,
+    pub enabled: bool,
+    /// The lowest optimization level the rule runs at, or None to run at
+    /// every level, as custom rules do.
+    pub level: Option<u8>,
+    pub safety: Option<Safety>,
+}
+
+impl RuleOptions {
+    pub fn new(name: Option<String>) -> Self {
+        RuleOptions {
+            name: name,
+            description: None,
+            priority: 0,
+            enabled: true,
+            level: None,
+            safety: None,
+        }
+    }
+}
+
+/**
+ * A registered rule. Every rule has a unique name, by which it can be
+ * disabled or removed.
+ */
+#[derive(Clone, Debug)]
+pub struct RuleEntry {
+    pub name: String,
+    pub description: Option<String>,
+    pub priority: i32,
+    pub enabled: bool,
+    pub level: Option<u8>,
+    pub safety: Option<Safety>,
+    pub rule: Arc<AstOptimizationRule>,
+}
+
+impl RuleEntry {
+    pub fn describe(&self) -> String {
+        match self.description {
+            Some(ref description) => description.clone(),
+            None => self.rule.describe(),
+        }
+    }
+}
+
+/**
+ * The registered rules, indexed by the kinds of node they can modify.
+ */
+#[derive(Clone)]
+pub struct Rules {
+    entries: Vec<RuleEntry>,
+    /// For each kind, the indices of the rules to try, in priority order.
+    by_kind: HashMap<php::zend_ast_kind, Vec<usize>>,
+    /// Rules which have to be tried on every node.
+    any_kind: Vec<usize>,
+    /// Used to name custom rules which weren't given a name.
+    next_id: usize,
+}
+
+impl Rules {
+    pub fn new() -> Self {
+        Rules {
+            entries: Vec::new(),
+            by_kind: HashMap::new(),
+            any_kind: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    /**
+     * Add a rule, returning its name. If no name is given, one is generated.
+     * Returns None if a rule with the same name already exists.
+     */
+    pub fn push(&mut self, options: RuleOptions, rule: Box<AstOptimizationRule>) -> Option<String> {
+        let name = match options.name {
+            Some(name) => name,
+            None => self.generate_name(|_| true),
+        };
+        if self.position(&name).is_some() {
+            return None;
+        }
+        // after any rules with the same or a higher priority
+        let priority = options.priority;
+        let index = self.entries.iter().position(|entry| entry.priority < priority)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(index, RuleEntry {
+            name: name.clone(),
+            description: options.description,
+            priority: priority,
+            enabled: options.enabled,
+            level: options.level,
+            safety: options.safety,
+            rule: Arc::from(rule),
+        });
+        self.reindex();
+        Some(name)
+    }
+
+    /**
+     * Remove the rule with the given name. Returns false if there isn't one.
+     */
+    pub fn remove(&mut self, name: &str) -> bool {
+        match self.position(name) {
+            Some(i) => {
+                self.entries.remove(i);
+                self.reindex();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /**
+     * Enable or disable the rule with the given name. Returns false if there
+     * isn't one.
+     */
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.position(name) {
+            Some(i) => {
+                self.entries[i].enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn entries(&self) -> &[RuleEntry] {
+        &self.entries
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.name == name)
+    }
+
+    /**
+     * Generate a name for a custom rule which isn't taken, and which
+     * `available` accepts.
+     */
+    fn generate_name<F>(&mut self, available: F) -> String where F: Fn(&str) -> bool {
+        loop {
+            let name = format!("custom_{}", self.next_id);
+            self.next_id += 1;
+            if self.position(&name).is_none() && available(&name) {
+                return name;
+            }
+        }
+    }
+
+    /**
+     * Rebuild the index of rules by kind.
+     */
+    fn reindex(&mut self) {
+        self.by_kind.clear();
+        self.any_kind.clear();
+        for (index, entry) in self.entries.iter().enumerate() {
+            match entry.rule.root_kinds() {
+                Some(kinds) => {
+                    for kind in kinds {
+                        let any_kind = &self.any_kind;
+                        self.by_kind.entry(kind).or_insert_with(|| any_kind.clone()).push(index);
+                    }
+                }
+                None => {
+                    self.any_kind.push(index);
+                    for indices in self.by_kind.values_mut() {
+                        indices.push(index);
+                    }
+                }
+            }
+        }
+    }
+
+    /**
+     * The rules which could match a node of the given kind.
+     */
+    fn for_kind(&self, kind: php::zend_ast_kind) -> &[usize] {
+        match self.by_kind.get(&kind) {
+            Some(indices) => indices,
+            None => &self.any_kind,
+        }
+    }
+}
 
 lazy_static! {
-    static ref OPTIMIZATIONS: Mutex<RulesVec> = {
-        let mut map: RulesVec = Vec::new();
-        map.push(Box::new(cond_elim::ConditionalElimination::new()));
-        map.push(Box::new(branch_elim::BranchElimination::new()));
-        map.push(Box::new(incr_decr::IncrDecr::new()));
-        map.push(Box::new(instanceof::InstanceOf::new()));
-        map.push(Box::new(loop_unroll::LoopUnroll::new()));
-        Mutex::new(map)
+    /**
+     * Rules which apply to every request: the built-in rules, and any loaded
+     * at module startup.
+     */
+    static ref PERSISTENT: Mutex<Arc<Rules>> = {
+        let mut rules = Rules::new();
+        {
+            // a built-in rule whose patterns don't compile is left out, rather
+            // than taking down the process
+            let mut builtin = |name: &str, level: u8, safety: Safety, priority: i32,
+                               rule: Result<Box<AstOptimizationRule>, String>| {
+                let rule = match rule {
+                    Ok(rule) => rule,
+                    Err(e) => {
+                        callback::warn(&format!("pphp: built-in rule {} wasn't added: {}", name, e));
+                        return;
+                    }
+                };
+                let mut options = RuleOptions::new(Some(name.to_string()));
+                options.priority = priority;
+                options.level = Some(level);
+                options.safety = Some(safety);
+                rules.push(options, rule);
+            };
+            // assumes the functions purity::is_pure knows haven't been
+            // shadowed by namespaced functions
+            builtin("ConditionalElimination", LEVEL_STANDARD, Safety::Unsafe, 0,
+                    boxed(cond_elim::ConditionalElimination::new()));
+            builtin("BranchElimination", LEVEL_SAFE, Safety::Safe, 0,
+                    boxed(branch_elim::BranchElimination::new()));
+            // `$x = $x + 1` and `++$x` differ for non-numeric strings and null
+            builtin("IncrDecr", LEVEL_STANDARD, Safety::Unsafe, 0,
+                    boxed(incr_decr::IncrDecr::new()));
+            // instanceof still works where is_a() has been disabled with
+            // disable_functions
+            builtin("InstanceOf", LEVEL_STANDARD, Safety::Unsafe, 0,
+                    boxed(instanceof::InstanceOf::new()));
+            // $i can also be changed through a reference or `global $i` in a
+            // function called by the body, which the copies wouldn't see
+            builtin("LoopUnroll", LEVEL_AGGRESSIVE, Safety::Unsafe, 0,
+                    boxed(loop_unroll::LoopUnroll::new()));
+            // assumes unqualified constants such as PHP_INT_SIZE haven't been
+            // shadowed by a namespaced constant. Tried after other rules, so
+            // that they see literal expressions before they're folded
+            builtin("ConstantFolding", LEVEL_STANDARD, Safety::Unsafe, -1,
+                    boxed(const_fold::ConstantFolding::new()));
+            // assumes constants declared in the file weren't already defined
+            // with another value, and that unqualified constants haven't been
+            // shadowed by a namespaced constant
+            builtin("ConstantPropagation", LEVEL_STANDARD, Safety::Unsafe, 0,
+                    Ok(Box::new(const_prop::ConstantPropagation::new())));
+            // backtraces lose the inlined call, and an unused argument which
+            // is an undefined variable no longer raises a notice
+            builtin("FunctionInlining", LEVEL_AGGRESSIVE, Safety::Unsafe, 0,
+                    Ok(Box::new(inline::FunctionInlining::new())));
+        }
+        Mutex::new(Arc::new(rules))
     };
 }
 
-pub fn add_rule(rule: Box<AstOptimizationRule>) {
-    let mut rules = OPTIMIZATIONS.lock().unwrap();
-    rules.push(rule);
+/**
+ * Box a rule from a built-in rule's constructor, which fails if the rule's
+ * patterns don't compile.
+ */
+fn boxed<R, E>(rule: Result<R, E>) -> Result<Box<AstOptimizationRule>, String>
+        where R: AstOptimizationRule + 'static, E: Display {
+    match rule {
+        Ok(rule) => Ok(Box::new(rule)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/**
+ * Lock the persistent rules. Rules are only added to them at startup, so if a
+ * panic poisoned the lock they're still intact and the lock is recovered.
+ */
+fn lock_persistent() -> MutexGuard<'static, Arc<Rules>> {
+    PERSISTENT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/**
+ * A snapshot of the persistent rules. The lock is only held while taking it,
+ * so that threads compiling at the same time don't wait on each other's
+ * rules (and PHP callbacks) being applied.
+ */
+fn persistent() -> Arc<Rules> {
+    lock_persistent().clone()
+}
+
+/**
+ * Rules added by the current request, which are dropped when it ends, along
+ * with the request's changes to whether rules are enabled.
+ */
+struct RequestRules {
+    rules: Rules,
+    overrides: HashMap<String, bool>,
+}
+
+thread_local! {
+    static REQUEST: RefCell<RequestRules> = RefCell::new(RequestRules {
+        rules: Rules::new(),
+        overrides: HashMap::new(),
+    });
+
+    /// Set while apply_all is running, when the rules are in use.
+    static APPLYING: Cell<bool> = Cell::new(false);
+}
+
+/**
+ * Returns true if rules are being applied on this thread, which is the case
+ * while a guard or rewrite callback is running.
+ */
+pub fn applying() -> bool {
+    APPLYING.with(|applying| applying.get())
+}
+
+/**
+ * Rules can't be changed while they're being applied; returns true, raising
+ * a warning, if that's what's being attempted.
+ */
+fn busy() -> bool {
+    let applying = applying();
+    if applying {
+        callback::warn("pphp: rules can't be changed or listed by a guard or rewrite callback");
+    }
+    applying
+}
+
+/**
+ * Clears APPLYING when dropped, even if a rule panics.
+ */
+struct Applying;
+
+impl Drop for Applying {
+    fn drop(&mut self) {
+        APPLYING.with(|applying| applying.set(false));
+    }
 }
 
+/**
+ * Information about a registered rule, for pphp_list_rules.
+ */
+pub struct RuleInfo {
+    pub name: String,
+    pub description: String,
+    pub priority: i32,
+    pub enabled: bool,
+    pub persistent: bool,
+    pub level: Option<u8>,
+    pub safety: Option<Safety>,
+}
+
+/**
+ * Register a rule which applies to every request. Returns its name, or None
+ * if a rule with that name already exists.
+ */
+pub fn add_persistent_rule(options: RuleOptions, rule: Box<AstOptimizationRule>) -> Option<String> {
+    if busy() {
+        return None;
+    }
+    let mut rules = lock_persistent();
+    // copies the rules only if a snapshot of them is still in use
+    Arc::make_mut(&mut rules).push(options, rule)
+}
+
+/**
+ * Register a rule for the rest of the current request. Returns its name, or
+ * None if a rule with that name already exists.
+ */
+pub fn add_rule(mut options: RuleOptions, rule: Box<AstOptimizationRule>) -> Option<String> {
+    if busy() {
+        return None;
+    }
+    let persistent = persistent();
+    REQUEST.with(|request| {
+        let mut request = request.borrow_mut();
+        let name = match options.name {
+            Some(name) => name,
+            None => request.rules.generate_name(|name| !persistent.contains(name)),
+        };
+        if persistent.contains(&name) {
+            return None;
+        }
+        options.name = Some(name);
+        request.rules.push(options, rule)
+    })
+}
+
+/**
+ * Returns true if a rule with the given name applies to the current request.
+ */
+pub fn rule_exists(name: &str) -> bool {
+    if busy() {
+        return false;
+    }
+    persistent().contains(name) ||
+        REQUEST.with(|request| request.borrow().rules.contains(name))
+}
+
+/**
+ * Remove a rule added by the current request. Persistent rules can't be
+ * removed, only disabled.
+ */
+pub fn remove_rule(name: &str) -> bool {
+    if busy() {
+        return false;
+    }
+    if persistent().contains(name) {
+        callback::warn(&format!(
+            "pphp: '{}' is a persistent rule and can't be removed; use pphp_disable_rule instead",
+            name
+        ));
+        return false;
+    }
+    REQUEST.with(|request| {
+        let mut request = request.borrow_mut();
+        request.overrides.remove(name);
+        request.rules.remove(name)
+    })
+}
+
+/**
+ * Enable or disable a rule for the rest of the current request. This takes
+ * precedence over pphp.disabled_rules and pphp.optimization_level.
+ */
+pub fn set_rule_enabled(name: &str, enabled: bool) -> bool {
+    if busy() {
+        return false;
+    }
+    let persistent = persistent().contains(name);
+    REQUEST.with(|request| {
+        let mut request = request.borrow_mut();
+        if !persistent && !request.rules.contains(name) {
+            return false;
+        }
+        request.overrides.insert(name.to_string(), enabled);
+        true
+    })
+}
+
+/**
+ * Information about every rule that applies to the current request:
+ * persistent rules and then the request's own, each in priority order.
+ */
+pub fn list_rules() -> Vec<RuleInfo> {
+    if busy() {
+        return Vec::new();
+    }
+    let settings = Settings::current();
+    let persistent = persistent();
+    REQUEST.with(|request| {
+        let request = request.borrow();
+        let info = |entry: &RuleEntry, persistent: bool| RuleInfo {
+            name: entry.name.clone(),
+            description: entry.describe(),
+            priority: entry.priority,
+            enabled: request.is_enabled(entry, &settings),
+            persistent: persistent,
+            level: entry.level,
+            safety: entry.safety,
+        };
+        let mut list: Vec<RuleInfo> = persistent.entries().iter().map(|entry| info(entry, true)).collect();
+        list.extend(request.rules.entries().iter().map(|entry| info(entry, false)));
+        list
+    })
+}
+
+/**
+ * Drop the current request's rules and overrides. Called at the end of each
+ * request.
+ */
+pub fn end_request() {
+    REQUEST.with(|request| {
+        let mut request = request.borrow_mut();
+        request.rules = Rules::new();
+        request.overrides.clear();
+    });
+    callback::end_request();
+}
+
+/**
+ * The INI settings which decide whether rules are enabled, read once rather
+ * than for every node.
+ */
+struct Settings {
+    disabled_rules: Vec<String>,
+    level: u8,
+}
+
+impl Settings {
+    fn current() -> Self {
+        let options = ast::options();
+        Settings {
+            disabled_rules: options.disabled_rules,
+            level: options.optimization_level,
+        }
+    }
+}
+
+impl RequestRules {
+    /**
+     * Whether a rule is enabled for this request: if it hasn't been enabled
+     * or disabled by name, that depends on how it was registered and the INI
+     * settings.
+     */
+    fn is_enabled(&self, entry: &RuleEntry, settings: &Settings) -> bool {
+        match self.overrides.get(&entry.name) {
+            Some(&enabled) => enabled,
+            None => {
+                entry.enabled && entry.level.map_or(true, |level| level <= settings.level) &&
+                    !settings.disabled_rules.contains(&entry.name)
+            }
+        }
+    }
+}
+
+/**
+ * The most passes apply_all will make over an AST before giving up.
+ */
+const MAX_PASSES: usize = 100;
+
+/**
+ * Apply all rules to an AST until it stops changing. If the rules don't reach
+ * a fixed point (because they undo each other's changes, or keep growing the
+ * AST) a warning is raised naming the rules involved, and the AST is left as
+ * it was after the last pass.
+ *
+ * Code compiled while rules are being applied, e.g. by an eval() in a guard,
+ * isn't optimized.
+ *
+ * If a guard or rewrite callback bails out, no more rules are applied; the
+ * caller re-raises the bailout once the rules have been released (see
+ * callback::take_bailout).
+ */
 pub fn apply_all(ast: ZendAst) {
-    let mut modified: bool = false;
-    {
-        let rules = OPTIMIZATIONS.lock().unwrap();
-        let mut context = PhpContext::new();
-        for rule in rules.iter() {
-            ast_walk!(ast, |ast_ptr: &mut ZendAstPtr, ctx: &mut PhpContext| {
-                rule.optimize(ast_ptr, ctx);
-                if ast_ptr.is_dirty() {
-                    ast_ptr.set_dirty(false);
-                    modified = true;
-                }
-            }, &mut context);
+    if APPLYING.with(|applying| applying.replace(true)) {
+        return;
+    }
+    let _applying = Applying;
+    let settings = Settings::current();
+    let persistent = persistent();
+    REQUEST.with(|request| {
+        let request = request.borrow();
+        // the hash of the AST before each pass, and the rules applied in it
+        let mut history: Vec<(u64, Vec<String>)> = Vec::new();
+        let mut hash = hash_tree(ast);
+        for _ in 0 .. MAX_PASSES {
+            let applied = apply_pass(&persistent, &request, &settings, ast);
+            if applied.is_empty() || callback::bailed_out() {
+                return;
+            }
+            history.push((hash, applied));
+            hash = hash_tree(ast);
+            if let Some(start) = history.iter().position(|&(h, _)| h == hash) {
+                callback::warn(&format!(
+                    "pphp: rules are rewriting code in a cycle and were stopped: {}",
+                    rules_applied(&history[start..]).join(", ")
+                ));
+                return;
+            }
+        }
+        callback::warn(&format!(
+            "pphp: code was still being rewritten after {} passes and was left as is; rules in the last pass: {}",
+            MAX_PASSES, rules_applied(&history[history.len() - 1..]).join(", ")
+        ));
+    });
+}
+
+fn rules_applied(passes: &[(u64, Vec<String>)]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for &(_, ref applied) in passes {
+        for name in applied {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
         }
     }
-    if modified {
-        apply_all(ast)
+    names
+}
+
+/**
+ * The persistent and request rules which could match a node, merged in
+ * priority order; persistent rules go first among those with the same
+ * priority. Both lists of indices are already in priority order.
+ */
+struct Candidates<'a> {
+    persistent: &'a Rules,
+    request: &'a Rules,
+    persistent_indices: ::std::slice::Iter<'a, usize>,
+    request_indices: ::std::slice::Iter<'a, usize>,
+}
+
+impl<'a> Iterator for Candidates<'a> {
+    type Item = &'a RuleEntry;
+
+    fn next(&mut self) -> Option<&'a RuleEntry> {
+        let persistent = self.persistent_indices.as_slice().first().map(|&i| &self.persistent.entries[i]);
+        let request = self.request_indices.as_slice().first().map(|&i| &self.request.entries[i]);
+        match (persistent, request) {
+            (Some(p), Some(r)) if r.priority > p.priority => {
+                self.request_indices.next();
+                Some(r)
+            }
+            (Some(p), _) => {
+                self.persistent_indices.next();
+                Some(p)
+            }
+            (None, r) => {
+                self.request_indices.next();
+                r
+            }
+        }
     }
 }
+
+/**
+ * Make a single pass over an AST, returning the names of the rules which
+ * modified it.
+ */
+fn apply_pass(persistent: &Rules, request: &RequestRules, settings: &Settings, ast: ZendAst) -> Vec<String> {
+    let mut applied: Vec<String> = Vec::new();
+    let mut context = PhpContext::new();
+    context::walk(ast, &mut context, &mut |ast_ptr: &mut ZendAstPtr, ctx: &mut PhpContext| {
+        if callback::bailed_out() {
+            return;
+        }
+        let kind = unsafe { (*unwrap_all(ast_ptr.deref())).kind };
+        let candidates = Candidates {
+            persistent: persistent,
+            request: &request.rules,
+            persistent_indices: persistent.for_kind(kind).iter(),
+            request_indices: request.rules.for_kind(kind).iter(),
+        };
+        for entry in candidates.filter(|entry| request.is_enabled(entry, settings)) {
+            entry.rule.optimize(ast_ptr, ctx);
+            if ast_ptr.is_dirty() {
+                // the node may have changed kind; the remaining rules
+                // get another chance on the next pass
+                ast_ptr.set_dirty(false);
+                if !applied.contains(&entry.name) {
+                    applied.push(entry.name.clone());
+                }
+                break;
+            }
+        }
+    });
+    applied
+}
@@ -1,4 +1,4 @@
 <?php
 
-pphp_add_rule("test_rule", '"hello world";', '100;');
+pphp_add_rule('"hello world";', '100;', null, "test_rule");
 include 'test_file.php';